@@ -1,24 +1,50 @@
 use std::{
-    collections::{
-        hash_map::Entry::{Occupied, Vacant},
-        HashMap,
-    },
-    sync::mpsc::Receiver,
+    collections::HashMap,
+    path::PathBuf,
+    time::{Duration, Instant},
 };
 
-use azure_storage::storage_shared_key_credential::StorageSharedKeyCredential;
+use crate::{
+    auth::AdlsAuth,
+    format::{self, Compression, FileFormat, Format},
+    health::HEALTH,
+    metrics::METRICS,
+    retry::RetryPolicy,
+    sink::{FileMetadata, LocalSink, Sink},
+    wal::WriteAheadLog,
+};
+use async_trait::async_trait;
+use azure_core::{headers::Headers, Context, CustomHeaders};
 use azure_storage_datalake::prelude::*;
 use bytes::Bytes;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use futures::stream::{FuturesUnordered, StreamExt};
 use log;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::mpsc::Receiver,
+    time::{interval, MissedTickBehavior},
+};
 use uuid::Uuid;
 
 /// Definition of what is expected by worker for writing to ADLS.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct WriteJob {
     pub path: String,
     pub payload: String,
     pub n_per_file: i32,
+    /// How the batch containing this job is encoded.
+    #[serde(default)]
+    pub format: FileFormat,
+    /// Event time of the message, used to name the file it is written to.
+    #[serde(default)]
+    pub time: Option<DateTime<Utc>>,
+    /// Where the batch is written. Unset fields use the global defaults.
+    #[serde(default)]
+    pub destination: Destination,
+    /// Id of the job in the write-ahead log, if it has been persisted.
+    #[serde(skip)]
+    pub wal_id: Option<String>,
 }
 
 impl Default for WriteJob {
@@ -27,111 +53,594 @@ impl Default for WriteJob {
             path: "".to_string(),
             payload: "".to_string(),
             n_per_file: 1,
+            format: FileFormat::default(),
+            time: None,
+            destination: Destination::default(),
+            wal_id: None,
         }
     }
 }
 
-pub async fn handle_write_jobs(
-    data_lake_client: DataLakeClient,
-    receiver: Receiver<WriteJob>,
-) -> azure_core::error::Result<()> {
-    // Initialize HashMap (dictionary) to hold <path, Vec<payload_str>>
-    let mut map: HashMap<String, Vec<String>> = HashMap::new();
-
-    // For every message received by receiver
-    for received in receiver {
-        log::debug!("Received: {:?}", received);
-        // If there is a path
-        if received.path != "" {
-            // Match on the path
-            match map.entry(received.path.to_string()) {
-                // If the path doesn't exist, then insert a vector with the payoad
-                Vacant(e) => {
-                    log::debug!("entry {} is vacant!", e.key());
-                    e.insert(vec![received.payload]);
-                }
-                // If the path does exist
-                Occupied(mut e) => {
-                    log::debug!("entry {} is occupied!", e.key());
-                    // Get the current vector of payloads from the topic as a mutable
-                    let v = e.get_mut();
-                    // Add the newly received payload to the vector
-                    v.push(received.payload);
-                    // If we have reached our write limit we flush our data
-                    if v.len() as i32 == received.n_per_file {
-                        log::info!(
-                            "Flushing {} lines to {}",
-                            &received.n_per_file,
-                            &received.path.to_string()
-                        );
-                        // Upload multiline json to datalake
+/// Container, directory and file extension of the files written for a path.
+///
+/// Routes can set each of them. Unset fields fall back to `ADLS_CONTAINER`,
+/// `ADLS_PREFIX` and `ADLS_FILE_EXTENSION`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Destination {
+    /// File system (container) in the storage account, e.g. `raw`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+    /// Directory in the container that paths are relative to, e.g. `bronze/mqtt`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    /// File extension without the leading dot. By default it follows from
+    /// the format, e.g. `json.gz`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension: Option<String>,
+}
+
+impl Destination {
+    /// Fill the unset fields from `defaults`.
+    pub fn or(&self, defaults: &Destination) -> Destination {
+        Destination {
+            container: self
+                .container
+                .clone()
+                .or_else(|| defaults.container.clone()),
+            prefix: self.prefix.clone().or_else(|| defaults.prefix.clone()),
+            extension: self
+                .extension
+                .clone()
+                .or_else(|| defaults.extension.clone()),
+        }
+    }
+
+    /// Check the container name against the naming rules of Azure Storage,
+    /// and that the extension is a plain file extension.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(container) = &self.container {
+            let valid = (3..=63).contains(&container.len())
+                && container
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+                && !container.starts_with('-')
+                && !container.ends_with('-')
+                && !container.contains("--");
+            if !valid {
+                return Err(format!(
+                    "Invalid container '{container}'. Use 3 to 63 lowercase letters, \
+                     digits and single hyphens, starting and ending with a letter or digit"
+                ));
+            }
+        }
+        if let Some(extension) = &self.extension {
+            if extension.is_empty() || extension.starts_with('.') || extension.contains('/') {
+                return Err(format!(
+                    "Invalid file extension '{extension}'. Leave out the leading dot"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Container and directory of the files for `path`, e.g. `raw/bronze/packml`.
+    pub fn location(&self, path: &str) -> String {
+        format!(
+            "{}/{}",
+            self.container.as_deref().unwrap_or_default(),
+            self.directory(path)
+        )
+    }
+
+    /// The directory of the files for `path`, below the prefix if any.
+    fn directory(&self, path: &str) -> String {
+        let path = path.trim_matches('/');
+        match self.prefix.as_deref().map(|p| p.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{prefix}/{path}"),
+            _ => path.to_string(),
+        }
+    }
+}
+
+/// Payloads buffered for a single path.
+#[derive(Debug)]
+struct Batch {
+    /// Container that the file is written to.
+    container: String,
+    /// Directory of the file, including the prefix.
+    directory: String,
+    /// Extension of the file, if not the one of the format.
+    extension: Option<String>,
+    lines: Vec<String>,
+    n_per_file: i32,
+    format: FileFormat,
+    opened: Instant,
+    /// Earliest event time of the lines.
+    first: Option<DateTime<Utc>>,
+    /// Write-ahead log entries to remove once the batch is uploaded.
+    wal_ids: Vec<String>,
+}
+
+impl Batch {
+    fn new(job: &WriteJob, destination: Destination) -> Batch {
+        Batch {
+            directory: destination.directory(&job.path),
+            container: destination.container.unwrap_or_default(),
+            extension: destination.extension,
+            lines: Vec::new(),
+            n_per_file: job.n_per_file,
+            format: job.format.clone(),
+            opened: Instant::now(),
+            first: None,
+            wal_ids: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, job: WriteJob) {
+        self.lines.push(job.payload);
+        self.wal_ids.extend(job.wal_id);
+        self.first = match (self.first, job.time) {
+            (Some(first), Some(time)) => Some(first.min(time)),
+            (first, time) => first.or(time),
+        };
+    }
+
+    fn is_full(&self) -> bool {
+        self.lines.len() as i32 >= self.n_per_file
+    }
+
+    /// Container and directory, used as the key of the batch.
+    fn location(&self) -> String {
+        format!("{}/{}", self.container, self.directory)
+    }
+}
+
+/// How the write loop batches, retries and dead-letters.
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    /// Container, prefix and extension of jobs whose route sets none.
+    pub defaults: Destination,
+    /// Maximum age of a batch. `None` writes batches only once they are full.
+    pub flush_interval: Option<Duration>,
+    pub retry: RetryPolicy,
+    /// Where batches go when every attempt to upload them failed.
+    pub dead_letter_dir: PathBuf,
+    /// Batches uploaded at the same time at most.
+    pub concurrency: usize,
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            defaults: Destination {
+                container: Some("raw".to_string()),
+                ..Default::default()
+            },
+            flush_interval: Some(Duration::from_secs(60)),
+            retry: RetryPolicy::default(),
+            dead_letter_dir: PathBuf::from("dead-letter"),
+            concurrency: 4,
+        }
+    }
+}
+
+/// Writes batches to a sink, retrying failed uploads.
+struct Writer<'a> {
+    sink: &'a dyn Sink,
+    wal: Option<&'a WriteAheadLog>,
+    retry: RetryPolicy,
+    /// Where batches go when every attempt to upload them failed.
+    dead_letter: LocalSink,
+}
+
+impl Writer<'_> {
+    /// Upload the lines of `batch` as a single file below its directory.
+    ///
+    /// Retries use the same file name, so a retry never leaves a duplicate
+    /// file. If every attempt fails, the batch is written as JSON to the
+    /// dead-letter directory instead. An error is only returned if that fails
+    /// as well.
+    async fn flush(&self, batch: Batch) -> azure_core::error::Result<()> {
+        let location = batch.location();
+        log::info!("Flushing {} lines to {}", batch.lines.len(), location);
+        let _ = METRICS.buffered_messages.remove_label_values(&[&location]);
+        let format = batch.format.format.as_str();
+        let container = batch.container.as_str();
+        let time = batch.first.unwrap_or_else(Utc::now);
+        let stem = new_file_stem(&batch.directory, time);
+        let extension = batch
+            .extension
+            .as_deref()
+            .unwrap_or_else(|| batch.format.extension());
+        let file_path = format!("{}.{}", stem, extension);
+
+        let uploaded = self
+            .retry
+            .run(&format!("upload of '{file_path}'"), || async {
+                let started = Instant::now();
+                let written = match batch.format.format {
+                    Format::Json => {
+                        let compression = batch.format.compression;
                         upload_json_multiline(
-                            &data_lake_client,
-                            "raw".to_string(),
-                            format!("rust-tests/{}", &received.path.to_string()),
-                            v.to_vec(),
-                            "json".to_string(),
+                            self.sink,
+                            container,
+                            &file_path,
+                            &batch.lines,
+                            compression,
                         )
-                        .await?;
-                        // Clear vector for new messages
-                        v.clear();
+                        .await
                     }
+                    Format::Parquet => {
+                        upload_parquet(
+                            self.sink,
+                            container,
+                            &file_path,
+                            &batch.lines,
+                            &batch.format,
+                        )
+                        .await
+                    }
+                };
+                let result = if written.is_ok() { "ok" } else { "error" };
+                METRICS
+                    .upload_duration
+                    .with_label_values(&[format, result])
+                    .observe(started.elapsed().as_secs_f64());
+                written
+            })
+            .await;
+
+        match &uploaded {
+            Ok(bytes) => {
+                HEALTH.set_upload_succeeded(true);
+                METRICS.batches_uploaded.with_label_values(&[format]).inc();
+                METRICS
+                    .bytes_uploaded
+                    .with_label_values(&[format])
+                    .inc_by(*bytes as u64);
+            }
+            // Data that can't be converted says nothing about the sink.
+            Err(e) if matches!(e.kind(), azure_core::error::ErrorKind::DataConversion) => {
+                METRICS.parse_failures.with_label_values(&[format]).inc();
+            }
+            Err(_) => HEALTH.set_upload_succeeded(false),
+        }
+
+        if let Err(e) = uploaded {
+            log::error!(
+                "Giving up on '{}/{}' after {} attempts: {}. Writing it to '{}'",
+                container,
+                file_path,
+                self.retry.max_attempts,
+                e,
+                self.dead_letter.root.display()
+            );
+            let json_path = format!("{}.json", stem);
+            upload_json_multiline(&self.dead_letter, container, &json_path, &batch.lines, None)
+                .await?;
+            METRICS.batches_dead_lettered.inc();
+        }
+
+        // The batch is stored, so its entries no longer need to be replayed.
+        if let Some(wal) = self.wal {
+            wal.remove(&batch.wal_ids)?;
+        }
+        Ok(())
+    }
+
+    /// Like `flush`, but log and count the error as well.
+    async fn flush_logged(&self, location: String, batch: Batch) -> azure_core::error::Result<()> {
+        let result = self.flush(batch).await;
+        if let Err(e) = &result {
+            log::error!("Error flushing {}: {}", location, e);
+            METRICS.batches_failed.inc();
+        }
+        result
+    }
+}
+
+/// Batch the jobs from `receiver` per destination and path and write them to
+/// `sink`.
+///
+/// Jobs without a container, prefix or extension take the one of
+/// `options.defaults`. Up to `options.concurrency` batches are uploaded at
+/// the same time. While that many are in progress no jobs are taken, so a
+/// slow sink fills the channel and holds back its senders. If `wal` is given,
+/// the log entries of a batch are removed once it has been written.
+pub async fn handle_write_jobs(
+    sink: &dyn Sink,
+    mut receiver: Receiver<WriteJob>,
+    wal: Option<&WriteAheadLog>,
+    options: &WriteOptions,
+) -> azure_core::error::Result<()> {
+    // Batches older than this are flushed even if they are not full.
+    let max_age = options.flush_interval;
+    // How often we wake up to look for old batches when no messages arrive.
+    let mut tick = interval(Duration::from_secs(1));
+    tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let writer = Writer {
+        sink,
+        wal,
+        retry: options.retry.clone(),
+        dead_letter: LocalSink::new(&options.dead_letter_dir),
+    };
+
+    // Initialize HashMap (dictionary) to hold <container/directory, Batch>
+    let mut map: HashMap<String, Batch> = HashMap::new();
+    // Uploads in progress.
+    let mut uploads = FuturesUnordered::new();
+    // The first batch that could not be stored, returned once the loop ends.
+    let mut result = Ok(());
+    let concurrency = options.concurrency.max(1);
+
+    loop {
+        tokio::select! {
+            received = receiver.recv(), if uploads.len() < concurrency => {
+                // The senders have hung up, so no more messages will arrive.
+                let Some(received) = received else {
+                    break;
+                };
+                HEALTH.write_loop.beat();
+                log::debug!("Received: {:?}", received);
+                // Messages without a path are not routed anywhere.
+                if !received.path.is_empty() {
+                    // Add the payload to the batch for its location, opening one if needed.
+                    let destination = received.destination.or(&options.defaults);
+                    let location = destination.location(&received.path);
+                    let batch = map
+                        .entry(location.clone())
+                        .or_insert_with(|| Batch::new(&received, destination));
+                    batch.push(received);
+                    METRICS
+                        .buffered_messages
+                        .with_label_values(&[&location])
+                        .set(batch.lines.len() as i64);
+                    log::debug!("Current Map: {:?}", map);
                 }
+            }
+            // A failed batch is kept in the write-ahead log, if enabled, and
+            // retried on the next start. The error has been logged and
+            // counted; the bridge keeps running, but exits with it.
+            Some(uploaded) = uploads.next(), if !uploads.is_empty() => {
+                HEALTH.write_loop.beat();
+                result = result.and(uploaded);
+            }
+            // An upload that hangs stops the heartbeat.
+            _ = tick.tick() => {
+                if uploads.is_empty() {
+                    HEALTH.write_loop.beat();
+                }
+            }
+        }
+
+        // Flush batches that are full or have waited too long for more
+        // messages, as far as there is room for more uploads.
+        let ready: Vec<String> = map
+            .iter()
+            .filter(|(_, batch)| {
+                batch.is_full() || max_age.is_some_and(|max_age| batch.opened.elapsed() >= max_age)
+            })
+            .map(|(location, _)| location.to_string())
+            .take(concurrency.saturating_sub(uploads.len()))
+            .collect();
+        for location in ready {
+            if let Some(batch) = map.remove(&location) {
+                uploads.push(writer.flush_logged(location, batch));
+            }
+        }
+    }
+
+    // Drain every buffered batch, next to the uploads in progress, before we
+    // return.
+    log::info!("Flushing {} buffered path(s) before exiting", map.len());
+    let mut batches = map.into_iter();
+    loop {
+        while uploads.len() < concurrency {
+            let Some((location, batch)) = batches.next() else {
+                break;
             };
-            log::debug!("Current Map: {:?}", map);
+            uploads.push(writer.flush_logged(location, batch));
         }
+        let Some(uploaded) = uploads.next().await else {
+            break;
+        };
+        result = result.and(uploaded);
     }
 
-    Ok(())
+    result
+}
+
+/// Construct the path of a new file below `path`, without extension.
+///
+/// File names are `{ts}-{uuid}`, where `ts` is the Unix time of `time`, so
+/// they are unique and sort by time.
+pub fn new_file_stem(path: &str, time: DateTime<Utc>) -> String {
+    format!(
+        "{path}/{ts}-{uid}",
+        ts = time.timestamp(),
+        uid = Uuid::new_v4(),
+    )
 }
 
+/// Write `data` as newline-delimited json to `file_path` in `container`.
+///
+/// Returns the number of bytes written.
 pub async fn upload_json_multiline(
-    data_lake_client: &DataLakeClient,
-    container: String,
-    path: String,
-    data: Vec<String>,
-    ext: String,
-) -> azure_core::error::Result<()> {
-    log::debug!("Creating file system client for {container}");
-    let file_system_client = data_lake_client.clone().into_file_system_client(&container);
+    sink: &dyn Sink,
+    container: &str,
+    file_path: &str,
+    data: &[String],
+    compression: Option<Compression>,
+) -> azure_core::error::Result<usize> {
+    log::debug!("Creating file '{}' with {} lines...", file_path, data.len());
+    let file_format = FileFormat {
+        compression,
+        ..Default::default()
+    };
+    let mut content = data.join("\n").into_bytes();
+    if let Some(compression) = compression {
+        content = format::compress(content, compression)?;
+    }
 
-    let now = Utc::now();
-    let file_name = format!(
-        "{ts}-{uid}.{ext}",
-        uid = &Uuid::new_v4(),
-        ts = &now.timestamp(),
-        ext = ext
-    );
-    let file_path = format!("{}/{}", path, file_name);
-    let file_client = file_system_client.get_file_client(&file_path);
+    let size = content.len();
+    sink.write(
+        container,
+        file_path,
+        Bytes::from(content),
+        &file_format.metadata(),
+    )
+    .await?;
+    Ok(size)
+}
 
-    log::debug!("Creating file '{}' with {} lines...", file_path, data.len());
-    let create_file_response = file_client.create().into_future().await?;
-    log::debug!("Create file response == {:?}\n", create_file_response);
+/// Write `data` as a single Parquet file to `file_path` in `container`.
+///
+/// Returns the number of bytes written.
+pub async fn upload_parquet(
+    sink: &dyn Sink,
+    container: &str,
+    file_path: &str,
+    data: &[String],
+    file_format: &FileFormat,
+) -> azure_core::error::Result<usize> {
+    log::debug!("Creating file '{}' with {} rows...", file_path, data.len());
+    let content = format::to_parquet(data, file_format)?;
 
-    let content = data.join("\n");
-    let mut offset = 0;
+    let size = content.len();
+    sink.write(container, file_path, content, &file_format.metadata())
+        .await?;
+    Ok(size)
+}
 
-    let byte_arr = Bytes::from(content.to_string());
-    let file_size = byte_arr.len() as i64;
+/// Writes files to the file systems (containers) of an Azure Datalake Gen2
+/// storage account.
+#[derive(Debug, Clone)]
+pub struct AdlsSink {
+    data_lake_client: DataLakeClient,
+}
 
-    log::debug!(
-        "appending '{:?}' to file '{}' at offset {}...",
-        byte_arr,
-        file_path,
-        offset
-    );
+impl AdlsSink {
+    pub fn new(data_lake_client: DataLakeClient) -> AdlsSink {
+        AdlsSink { data_lake_client }
+    }
 
-    let append_to_file = file_client.append(offset, byte_arr).into_future().await?;
-    log::debug!("append to file response == {:?}\n", append_to_file);
+    fn file_system_client(&self, container: &str) -> FileSystemClient {
+        log::debug!("Creating file system client for {container}");
+        self.data_lake_client
+            .clone()
+            .into_file_system_client(container)
+    }
+}
 
-    offset += file_size;
+#[async_trait]
+impl Sink for AdlsSink {
+    /// The metadata is stored as the Content-Type and Content-Encoding of the
+    /// file, as `x-ms-content-type` and `x-ms-content-encoding` on create.
+    async fn write(
+        &self,
+        container: &str,
+        file_path: &str,
+        content: Bytes,
+        metadata: &FileMetadata,
+    ) -> azure_core::error::Result<()> {
+        let file_client = self
+            .file_system_client(container)
+            .get_file_client(file_path);
 
-    log::debug!("flushing file '{}'...", file_path);
-    let flush_file_response = file_client.flush(offset).close(true).into_future().await?;
-    log::debug!("flush file response == {:?}\n", flush_file_response);
+        // The builder has no setters for them, so they are added by the
+        // custom headers policy of the pipeline, before the request is signed.
+        let mut headers = Headers::from(HashMap::new());
+        headers.insert("x-ms-content-type", metadata.content_type);
+        if let Some(content_encoding) = metadata.content_encoding {
+            headers.insert("x-ms-content-encoding", content_encoding);
+        }
+        let mut context = Context::new();
+        context.insert(CustomHeaders::from(headers));
 
-    Ok(())
+        log::debug!("Creating file '{}'...", file_path);
+        let create_file_response = file_client
+            .create()
+            .context(context)
+            .into_future()
+            .await
+            .inspect_err(|_| count_error("create"))?;
+        log::debug!("Create file response == {:?}\n", create_file_response);
+
+        let mut offset = 0;
+        let file_size = content.len() as i64;
+
+        log::debug!(
+            "appending '{:?}' to file '{}' at offset {}...",
+            content,
+            file_path,
+            offset
+        );
+
+        let append_to_file = file_client
+            .append(offset, content)
+            .into_future()
+            .await
+            .inspect_err(|_| count_error("append"))?;
+        log::debug!("append to file response == {:?}\n", append_to_file);
+
+        offset += file_size;
+
+        log::debug!("flushing file '{}'...", file_path);
+        let flush_file_response = file_client
+            .flush(offset)
+            .close(true)
+            .into_future()
+            .await
+            .inspect_err(|_| count_error("flush"))?;
+        log::debug!("flush file response == {:?}\n", flush_file_response);
+
+        Ok(())
+    }
+
+    /// Look the file system up, and create it if it is missing and `create`
+    /// is set.
+    async fn check_container(
+        &self,
+        container: &str,
+        create: bool,
+    ) -> azure_core::error::Result<()> {
+        let file_system_client = self.file_system_client(container);
+        match file_system_client.get_properties().into_future().await {
+            Ok(_) => return Ok(()),
+            Err(e) if is_not_found(&e) && create => {}
+            Err(e) if is_not_found(&e) => {
+                return Err(azure_core::error::Error::full(
+                    azure_core::error::ErrorKind::Other,
+                    e,
+                    format!(
+                        "Container '{container}' does not exist. \
+                         Set ADLS_CREATE_CONTAINER=true to create it"
+                    ),
+                ))
+            }
+            Err(e) => return Err(e),
+        }
+        log::info!("Creating container '{container}'");
+        file_system_client
+            .create()
+            .into_future()
+            .await
+            .inspect_err(|_| count_error("create_container"))?;
+        Ok(())
+    }
+}
+
+fn is_not_found(e: &azure_core::error::Error) -> bool {
+    matches!(
+        e.kind(),
+        azure_core::error::ErrorKind::HttpResponse { status: 404, .. }
+    )
+}
+
+/// Count a failed request to ADLS.
+fn count_error(operation: &str) {
+    METRICS.adls_errors.with_label_values(&[operation]).inc();
 }
 
 #[allow(unused)]
@@ -151,7 +660,6 @@ pub async fn upload_data_single(
     let create_file_response = file_client.create().into_future().await?;
     log::debug!("create file response == {:?}\n", create_file_response);
 
-    let data = data;
     let mut offset = 0;
 
     for el in data.iter() {
@@ -179,15 +687,15 @@ pub async fn upload_data_single(
     Ok(())
 }
 
-#[allow(unused)]
-pub async fn create_data_lake_client() -> azure_core::error::Result<DataLakeClient> {
-    let account_name = std::env::var("ADLSGEN2_STORAGE_ACCOUNT_NAME")
-        .expect("Set env variable ADLSGEN2_STORAGE_ACCOUNT first!");
-    let account_key = std::env::var("ADLSGEN2_STORAGE_ACCOUNT_KEY")
-        .expect("Set env variable ADLSGEN2_STORAGE_ACCESS_KEY first!");
-
-    Ok(DataLakeClient::new(
-        StorageSharedKeyCredential::new(account_name, account_key),
-        None,
-    ))
+/// Create a client for `account_name` that authenticates with `auth`.
+pub async fn create_data_lake_client(
+    account_name: &str,
+    auth: AdlsAuth,
+) -> azure_core::error::Result<DataLakeClient> {
+    log::info!(
+        "Authenticating to '{}' with ADLS_AUTH={}",
+        account_name,
+        auth.mode().as_str()
+    );
+    auth.data_lake_client(account_name.to_string(), azure_core::new_http_client())
 }
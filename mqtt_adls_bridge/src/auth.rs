@@ -0,0 +1,492 @@
+use crate::config;
+use async_trait::async_trait;
+use azure_core::{
+    auth::{TokenCredential, TokenResponse},
+    error::{Error, ErrorKind, Result, ResultExt},
+    ClientOptions, Context, HttpClient, Policy, PolicyResult, Request, TransportOptions,
+};
+use azure_storage::storage_shared_key_credential::StorageSharedKeyCredential;
+use azure_storage_datalake::prelude::DataLakeClient;
+use chrono::{Duration, Utc};
+use http::Method;
+use oauth2::AccessToken;
+use openssl::{
+    hash::MessageDigest,
+    pkcs12::Pkcs12,
+    pkey::{PKey, Private},
+    sign::Signer,
+    x509::X509,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{fs, str::FromStr, sync::Arc};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Endpoint of the Azure Instance Metadata Service, which hands out tokens of
+/// managed identities.
+const IMDS_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+
+/// Tokens are refreshed when they expire in less than this.
+const REFRESH_MARGIN_SECS: i64 = 300;
+
+/// The ways to authenticate, as selected by `ADLS_AUTH`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMode {
+    #[default]
+    SharedKey,
+    Sas,
+    ClientSecret,
+    ClientCertificate,
+    WorkloadIdentity,
+    ManagedIdentity,
+}
+
+impl AuthMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthMode::SharedKey => "shared_key",
+            AuthMode::Sas => "sas",
+            AuthMode::ClientSecret => "client_secret",
+            AuthMode::ClientCertificate => "client_certificate",
+            AuthMode::WorkloadIdentity => "workload_identity",
+            AuthMode::ManagedIdentity => "managed_identity",
+        }
+    }
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<AuthMode, String> {
+        config::parse_name(s)
+    }
+}
+
+/// How the bridge authenticates against Azure Datalake Gen2.
+#[derive(Clone)]
+pub enum AdlsAuth {
+    /// The storage account key.
+    SharedKey { account_key: String },
+    /// A shared access signature, e.g. `sv=...&sig=...`.
+    Sas { token: String },
+    /// A service principal with a client secret.
+    ClientSecret {
+        authority_host: String,
+        tenant_id: String,
+        client_id: String,
+        client_secret: String,
+    },
+    /// A service principal with a certificate, as PEM (certificate and
+    /// private key) or PKCS #12.
+    ClientCertificate {
+        authority_host: String,
+        tenant_id: String,
+        client_id: String,
+        certificate_path: String,
+        password: String,
+    },
+    /// A Kubernetes service account federated with an Azure AD application.
+    WorkloadIdentity {
+        authority_host: String,
+        tenant_id: String,
+        client_id: String,
+        token_file: String,
+    },
+    /// The managed identity of the VM or node. `client_id` selects a
+    /// user-assigned identity.
+    ManagedIdentity { client_id: Option<String> },
+}
+
+impl AdlsAuth {
+    /// The mode of these credentials.
+    pub fn mode(&self) -> AuthMode {
+        match self {
+            AdlsAuth::SharedKey { .. } => AuthMode::SharedKey,
+            AdlsAuth::Sas { .. } => AuthMode::Sas,
+            AdlsAuth::ClientSecret { .. } => AuthMode::ClientSecret,
+            AdlsAuth::ClientCertificate { .. } => AuthMode::ClientCertificate,
+            AdlsAuth::WorkloadIdentity { .. } => AuthMode::WorkloadIdentity,
+            AdlsAuth::ManagedIdentity { .. } => AuthMode::ManagedIdentity,
+        }
+    }
+
+    /// Create a client for `account_name` that authenticates with this mode.
+    ///
+    /// Both the storage requests and the Azure AD token requests are sent
+    /// through `http_client`.
+    pub fn data_lake_client(
+        self,
+        account_name: String,
+        http_client: Arc<dyn HttpClient>,
+    ) -> Result<DataLakeClient> {
+        let options = ClientOptions::new(TransportOptions::new(http_client.clone()));
+        let source = match self {
+            AdlsAuth::SharedKey { account_key } => {
+                return Ok(DataLakeClient::new_with_shared_key(
+                    StorageSharedKeyCredential::new(account_name, account_key),
+                    None,
+                    options,
+                ));
+            }
+            AdlsAuth::Sas { token } => {
+                let policy = Arc::new(SasAuthorizationPolicy::new(&token));
+                return Ok(DataLakeClient::new_with_auth_policy(
+                    policy,
+                    account_name,
+                    None,
+                    options,
+                ));
+            }
+            AdlsAuth::ClientSecret {
+                authority_host,
+                tenant_id,
+                client_id,
+                client_secret,
+            } => TokenSource::ClientSecret {
+                token_url: token_url(&authority_host, &tenant_id),
+                client_id,
+                client_secret,
+            },
+            AdlsAuth::ClientCertificate {
+                authority_host,
+                tenant_id,
+                client_id,
+                certificate_path,
+                password,
+            } => {
+                let (certificate, key) = load_certificate(&certificate_path, &password)?;
+                TokenSource::ClientCertificate {
+                    token_url: token_url(&authority_host, &tenant_id),
+                    client_id,
+                    certificate,
+                    key,
+                }
+            }
+            AdlsAuth::WorkloadIdentity {
+                authority_host,
+                tenant_id,
+                client_id,
+                token_file,
+            } => TokenSource::WorkloadIdentity {
+                token_url: token_url(&authority_host, &tenant_id),
+                client_id,
+                token_file,
+            },
+            AdlsAuth::ManagedIdentity { client_id } => TokenSource::ManagedIdentity { client_id },
+        };
+
+        let credential = Arc::new(AadCredential {
+            http_client,
+            source,
+            cached: Mutex::new(None),
+        });
+        Ok(DataLakeClient::new_with_token_credential(
+            credential,
+            account_name,
+            None,
+            options,
+        ))
+    }
+}
+
+/// The OAuth 2.0 token endpoint of `tenant_id` at `authority_host`, e.g.
+/// `https://login.microsoftonline.com`.
+fn token_url(authority_host: &str, tenant_id: &str) -> String {
+    format!(
+        "{}/{}/oauth2/v2.0/token",
+        authority_host.trim_end_matches('/'),
+        tenant_id
+    )
+}
+
+/// Load the certificate and private key of a service principal.
+fn load_certificate(path: &str, password: &str) -> Result<(X509, PKey<Private>)> {
+    let content = fs::read(path).with_context(ErrorKind::Credential, || {
+        format!("Error reading the certificate '{path}'")
+    })?;
+    let invalid = || format!("Invalid certificate '{path}'");
+
+    if path.ends_with(".pfx") || path.ends_with(".p12") {
+        let parsed = Pkcs12::from_der(&content)
+            .and_then(|pkcs12| pkcs12.parse2(password))
+            .with_context(ErrorKind::Credential, invalid)?;
+        return match (parsed.cert, parsed.pkey) {
+            (Some(certificate), Some(key)) => Ok((certificate, key)),
+            _ => Err(Error::with_message(ErrorKind::Credential, invalid)),
+        };
+    }
+
+    let certificate = X509::from_pem(&content).with_context(ErrorKind::Credential, invalid)?;
+    let key = if password.is_empty() {
+        PKey::private_key_from_pem(&content)
+    } else {
+        PKey::private_key_from_pem_passphrase(&content, password.as_bytes())
+    }
+    .with_context(ErrorKind::Credential, invalid)?;
+    Ok((certificate, key))
+}
+
+/// Signs requests with a shared access signature by appending it to the URL.
+#[derive(Debug, Clone)]
+pub struct SasAuthorizationPolicy {
+    query: String,
+}
+
+impl SasAuthorizationPolicy {
+    pub fn new(token: &str) -> SasAuthorizationPolicy {
+        SasAuthorizationPolicy {
+            query: token.trim_start_matches('?').to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Policy for SasAuthorizationPolicy {
+    async fn send(
+        &self,
+        ctx: &Context,
+        request: &mut Request,
+        next: &[Arc<dyn Policy>],
+    ) -> PolicyResult {
+        let uri = request.uri().to_string();
+        let separator = if request.uri().query().is_some() {
+            '&'
+        } else {
+            '?'
+        };
+        let mut signed = Request::new(
+            Request::parse_uri(&format!("{uri}{separator}{}", self.query))?,
+            request.method(),
+        );
+        *signed.headers_mut() = request.headers().clone();
+        signed.set_body(request.body().clone());
+        *request = signed;
+
+        next[0].send(ctx, request, &next[1..]).await
+    }
+}
+
+/// Where an Azure AD token comes from.
+enum TokenSource {
+    ClientSecret {
+        token_url: String,
+        client_id: String,
+        client_secret: String,
+    },
+    ClientCertificate {
+        token_url: String,
+        client_id: String,
+        certificate: X509,
+        key: PKey<Private>,
+    },
+    WorkloadIdentity {
+        token_url: String,
+        client_id: String,
+        token_file: String,
+    },
+    ManagedIdentity {
+        client_id: Option<String>,
+    },
+}
+
+/// Token endpoint response. The managed identity endpoint returns numbers as
+/// strings.
+#[derive(Debug, Deserialize)]
+struct Token {
+    access_token: String,
+    #[serde(deserialize_with = "number_or_string")]
+    expires_in: i64,
+}
+
+fn number_or_string<'de, D>(deserializer: D) -> std::result::Result<i64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(n) => n.as_i64(),
+        serde_json::Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| serde::de::Error::custom("expected a number of seconds"))
+}
+
+/// A credential that gets tokens from Azure AD and keeps them until shortly
+/// before they expire.
+struct AadCredential {
+    http_client: Arc<dyn HttpClient>,
+    source: TokenSource,
+    cached: Mutex<Option<TokenResponse>>,
+}
+
+impl std::fmt::Debug for AadCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("AadCredential").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl TokenCredential for AadCredential {
+    async fn get_token(&self, resource: &str) -> Result<TokenResponse> {
+        let mut cached = self.cached.lock().await;
+        if let Some(token) = cached.as_ref() {
+            if token.expires_on - Utc::now() > Duration::seconds(REFRESH_MARGIN_SECS) {
+                return Ok(token.clone());
+            }
+        }
+
+        log::debug!("Requesting an Azure AD token for {resource}");
+        let token = self.request_token(resource).await?;
+        let token = TokenResponse::new(
+            AccessToken::new(token.access_token),
+            Utc::now() + Duration::seconds(token.expires_in),
+        );
+        *cached = Some(token.clone());
+        Ok(token)
+    }
+}
+
+impl AadCredential {
+    async fn request_token(&self, resource: &str) -> Result<Token> {
+        let scope = format!("{}/.default", resource.trim_end_matches('/'));
+        let request = match &self.source {
+            TokenSource::ClientSecret {
+                token_url,
+                client_id,
+                client_secret,
+            } => form_request(
+                token_url,
+                &[
+                    ("grant_type", "client_credentials"),
+                    ("client_id", client_id),
+                    ("client_secret", client_secret),
+                    ("scope", &scope),
+                ],
+            )?,
+            TokenSource::ClientCertificate {
+                token_url,
+                client_id,
+                certificate,
+                key,
+            } => {
+                let assertion = client_assertion(token_url, client_id, certificate, key)?;
+                assertion_request(token_url, client_id, &assertion, &scope)?
+            }
+            TokenSource::WorkloadIdentity {
+                token_url,
+                client_id,
+                token_file,
+            } => {
+                // The kubelet rotates the file, so it is read for every token.
+                let assertion = fs::read_to_string(token_file)
+                    .with_context(ErrorKind::Credential, || {
+                        format!("Error reading the federated token '{token_file}'")
+                    })?;
+                assertion_request(token_url, client_id, assertion.trim(), &scope)?
+            }
+            TokenSource::ManagedIdentity { client_id } => {
+                let mut url = url::Url::parse(IMDS_ENDPOINT).expect("The endpoint is valid");
+                url.query_pairs_mut()
+                    .append_pair("api-version", "2018-02-01")
+                    .append_pair("resource", resource);
+                if let Some(client_id) = client_id {
+                    url.query_pairs_mut().append_pair("client_id", client_id);
+                }
+                let mut request = Request::new(Request::parse_uri(url.as_str())?, Method::GET);
+                request.headers_mut().insert("Metadata", "true");
+                request
+            }
+        };
+
+        let response = self.http_client.execute_request2(&request).await?;
+        let status = response.status();
+        let body = response.into_body().await;
+        if !status.is_success() {
+            return Err(Error::with_message(ErrorKind::Credential, || {
+                format!(
+                    "Azure AD returned {} for a token request: {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                )
+            }));
+        }
+        serde_json::from_slice(&body).context(ErrorKind::Credential, "Invalid token response")
+    }
+}
+
+/// A `POST` of `fields` as a form.
+fn form_request(url: &str, fields: &[(&str, &str)]) -> Result<Request> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(fields)
+        .finish();
+    let mut request = Request::new(Request::parse_uri(url)?, Method::POST);
+    request
+        .headers_mut()
+        .insert("Content-Type", "application/x-www-form-urlencoded");
+    request.set_body(body);
+    Ok(request)
+}
+
+/// A client credentials request authenticated with a signed `assertion`.
+fn assertion_request(url: &str, client_id: &str, assertion: &str, scope: &str) -> Result<Request> {
+    form_request(
+        url,
+        &[
+            ("grant_type", "client_credentials"),
+            ("client_id", client_id),
+            (
+                "client_assertion_type",
+                "urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+            ),
+            ("client_assertion", assertion),
+            ("scope", scope),
+        ],
+    )
+}
+
+/// A JWT for `token_url`, signed with the private key of the certificate.
+fn client_assertion(
+    token_url: &str,
+    client_id: &str,
+    certificate: &X509,
+    key: &PKey<Private>,
+) -> Result<String> {
+    let signing = |e| {
+        Error::full(
+            ErrorKind::Credential,
+            e,
+            "Error signing the client assertion",
+        )
+    };
+
+    let thumbprint = certificate.digest(MessageDigest::sha1()).map_err(signing)?;
+    let now = Utc::now().timestamp();
+    let header = json!({
+        "alg": "RS256",
+        "typ": "JWT",
+        "x5t": base64::encode_config(thumbprint, base64::URL_SAFE_NO_PAD),
+    });
+    let claims = json!({
+        "aud": token_url,
+        "iss": client_id,
+        "sub": client_id,
+        "jti": Uuid::new_v4().to_string(),
+        "nbf": now,
+        "exp": now + 600,
+    });
+    let message = format!(
+        "{}.{}",
+        base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD),
+        base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+    );
+
+    let mut signer = Signer::new(MessageDigest::sha256(), key).map_err(signing)?;
+    signer.update(message.as_bytes()).map_err(signing)?;
+    let signature = signer.sign_to_vec().map_err(signing)?;
+    Ok(format!(
+        "{}.{}",
+        message,
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    ))
+}
@@ -1,35 +1,367 @@
+use azure_core::error::{Error, ErrorKind};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use dotenv::dotenv;
 use mqtt_adls_bridge::{
-    adls::{create_data_lake_client, handle_write_jobs, WriteJob},
-    mqtt::start_mqtt_thread,
-    utils::init_log,
+    adls::{handle_write_jobs, WriteOptions},
+    config::BridgeConfig,
+    mqtt::run_mqtt_client,
+    replay::{self, ArchivedMessage, ReplayOptions, ReplayStats},
+    routing::Router,
+    server,
+    sink::{check_containers, create_sink},
+    utils::{init_log, shutdown_signal},
 };
 
 use std::{
-    sync::mpsc::{self, Receiver, Sender},
-    thread::JoinHandle,
+    env,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Duration,
 };
+use tokio::{
+    sync::{mpsc, watch},
+    task,
+};
+
+/// Bridge messages from an MQTT broker to Azure Datalake Gen2.
+///
+/// Settings come from the configuration file and the environment, see the
+/// README. The options below take precedence over both.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// TOML or YAML configuration file.
+    #[arg(short, long, global = true, env = "CONFIG_FILE", value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Log filter, e.g. `debug` or `mqtt_adls_bridge=debug`. Overrides RUST_LOG.
+    #[arg(short, long, global = true, value_name = "LEVEL")]
+    log_level: Option<String>,
+    /// Where files are written. Overrides SINK.
+    #[arg(long, global = true, value_parser = ["adls", "local"])]
+    sink: Option<String>,
+    /// Same as `validate-config --print`, kept for earlier versions.
+    #[arg(long, hide = true)]
+    print_config: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Bridge messages from the broker to the sink. This is the default.
+    Run,
+    /// Check the configuration, report every problem and exit.
+    ValidateConfig {
+        /// Print the effective configuration as TOML, with secrets masked.
+        #[arg(long)]
+        print: bool,
+    },
+    /// Print where messages would be written, without connecting or uploading.
+    DryRun {
+        /// Files with one message per line. Standard input is read if none are given.
+        files: Vec<PathBuf>,
+    },
+    /// Route the messages in the files below a directory and write them to the sink.
+    Replay {
+        /// Directory with archived messages, or a single file: files written with
+        /// `ENVELOPE=true`, dead letters or raw captures. Files without the envelope
+        /// have no topics and can't be replayed.
+        dir: PathBuf,
+        /// Messages per second at most.
+        #[arg(long, value_parser = parse_rate)]
+        rate: Option<f64>,
+        /// Only messages from this time on, e.g. `2024-05-01` or `2024-05-01T12:00:00Z`.
+        #[arg(long, value_parser = replay::parse_time)]
+        from: Option<DateTime<Utc>>,
+        /// Only messages before this time.
+        #[arg(long, value_parser = replay::parse_time)]
+        until: Option<DateTime<Utc>>,
+    },
+}
+
+/// A positive number of messages per second.
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("Invalid rate '{s}'. Use a positive number")),
+    }
+}
 
 /////////////////////////////////////////////////////////////////////////////
 
 #[tokio::main]
 async fn main() -> azure_core::error::Result<()> {
+    // Possibly load .env file, before the arguments that may come from it.
+    dotenv().ok();
+    let cli = Cli::parse();
+    let command = match cli.command {
+        _ if cli.print_config => Command::ValidateConfig { print: true },
+        command => command.unwrap_or(Command::Run),
+    };
+
+    // Read the optional configuration file and override it with the
+    // environment, and that with the options. A dry run uploads nothing, so
+    // it needs no credentials.
+    let sink = match command {
+        Command::DryRun { .. } => Some("local".to_string()),
+        _ => cli.sink,
+    };
+    let config = BridgeConfig::load_with(cli.config.as_deref(), |key| match key {
+        "RUST_LOG" if cli.log_level.is_some() => cli.log_level.clone(),
+        "SINK" if sink.is_some() => sink.clone(),
+        _ => env::var(key).ok(),
+    });
+
     // Initialize Logging
-    init_log();
+    let level = match &config {
+        Ok(config) => config.log_level.clone(),
+        Err(_) => cli
+            .log_level
+            .or_else(|| env::var("RUST_LOG").ok())
+            .unwrap_or_else(|| "info".to_string()),
+    };
+    init_log(&level);
+
+    // Report every problem at once, rather than one per restart.
+    let config = config.unwrap_or_else(|e| {
+        for problem in &e.problems {
+            log::error!("Invalid configuration: {}", problem);
+        }
+        process::exit(1);
+    });
+    if let Some(file) = &cli.config {
+        log::info!("Loaded configuration from '{}'", file.display());
+    }
+
+    match command {
+        Command::Run => run(config).await,
+        Command::ValidateConfig { print } => {
+            if print {
+                print!("{}", config.masked().to_toml());
+            } else {
+                log::info!("The configuration is valid");
+            }
+            Ok(())
+        }
+        Command::DryRun { files } => dry_run(&config, &files),
+        Command::Replay {
+            dir,
+            rate,
+            from,
+            until,
+        } => {
+            let options = ReplayOptions {
+                rate,
+                from,
+                until,
+                version: config.mqtt.version,
+            };
+            replay(&config, &dir, options).await
+        }
+    }
+}
+
+/// Containers that are written to, whether by a route or by default.
+fn containers(router: &Router, write_options: &WriteOptions) -> Vec<String> {
+    let mut containers = router.containers();
+    containers.extend(write_options.defaults.container.clone());
+    containers.sort();
+    containers.dedup();
+    containers
+}
+
+/// Bridge messages from the broker to the sink until we are asked to stop.
+async fn run(config: BridgeConfig) -> azure_core::error::Result<()> {
+    // The MQTT client passes messages to the write loop through a bounded
+    // channel. When it is full, the client stops acknowledging messages until
+    // there is room again.
+    let (transmitter, receiver) = mpsc::channel(config.queue_capacity);
+
+    // Everything below was checked by `BridgeConfig::load`, except what
+    // touches the filesystem or the network.
+    let router = config.mqtt.router()?;
+    let subscriptions = config.mqtt.subscriptions()?;
+    let records = config.record_format()?;
+    let write_options = config.write_options()?;
 
-    // Create Sender and Receiver to pass messages between two threads.
-    // One thread will run the MQTT client, and the other will send messages to ADLS.
-    let (transmitter, receiver): (Sender<WriteJob>, Receiver<WriteJob>) = mpsc::channel();
+    let containers = containers(&router, &write_options);
+
+    // Serve the metrics for Prometheus and the probes for Kubernetes.
+    server::start_server(
+        config.http_addr()?,
+        Duration::from_secs(config.http.max_stall_secs),
+    )
+    .unwrap_or_else(|e| {
+        log::error!("Error starting the HTTP server: {}", e);
+        process::exit(1);
+    });
+
+    // Open the optional write-ahead log and replay what was not uploaded
+    // before the last shutdown.
+    let wal = config.wal().unwrap_or_else(|e| {
+        log::error!("Error opening the write-ahead log: {}", e);
+        process::exit(1);
+    });
+    if let Some(wal) = &wal {
+        let jobs = wal.replay()?;
+        log::info!(
+            "Replaying {} message(s) from the write-ahead log",
+            jobs.len()
+        );
+        // The log may hold more than fits into the channel, so the jobs are
+        // sent while the write loop is already taking them.
+        let backlog = transmitter.clone();
+        tokio::spawn(async move {
+            for job in jobs {
+                if backlog.send(job).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Stop consuming and drain all buffers on SIGINT or SIGTERM.
+    // A second signal exits immediately.
+    let (shutdown, _) = watch::channel(false);
+    let flag = shutdown.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::info!("Shutting down. Send the signal again to exit immediately.");
+        flag.send_replace(true);
+        shutdown_signal().await;
+        process::exit(130);
+    });
+
+    // Create the sink that files are written to, by default the datalake.
+    let sink = create_sink(&config).await?;
+
+    // Fail early if a container is missing, before the MQTT client
+    // subscribes and acknowledges messages that could not be written.
+    check_containers(
+        sink.as_ref(),
+        containers.iter().map(String::as_str),
+        config.adls.create_container,
+    )
+    .await?;
+
+    // Run the MQTT client in a task of its own, next to the write loop.
+    let mqtt_client = tokio::spawn(run_mqtt_client(
+        transmitter,
+        config.mqtt.connect_options(),
+        router,
+        subscriptions,
+        records,
+        config.dead_letter_target(),
+        wal.clone(),
+        shutdown.clone(),
+    ));
+
+    // Handle messages received from the MQTT client until it stops,
+    // then flush everything that is still buffered.
+    let result = handle_write_jobs(sink.as_ref(), receiver, wal.as_ref(), &write_options).await;
+
+    // Stop the MQTT client as well, in case the write loop failed.
+    shutdown.send_replace(true);
+
+    // Wait for the MQTT client to finish. Its error takes precedence, since
+    // it is what stopped the write loop. If it panicked, the panic has been
+    // printed already.
+    let result = mqtt_client
+        .await
+        .map_err(|e| Error::full(ErrorKind::Other, e, "The MQTT client failed"))
+        .and_then(|stopped| stopped)
+        .and(result);
+
+    match &result {
+        Ok(()) => log::info!("All buffered messages written. Bye!"),
+        Err(e) => log::error!("Stopped with an error: {}", e),
+    }
+    result
+}
+
+/// Print the location every message in `files` would be written to, or why
+/// it would be dead-lettered.
+fn dry_run(config: &BridgeConfig, files: &[PathBuf]) -> azure_core::error::Result<()> {
+    let router = config.mqtt.router()?;
+    let records = config.record_format()?;
+    let defaults = config.write_options()?.defaults;
+
+    // Messages are routed as they are read, so the files may be large.
+    let mut out = io::stdout().lock();
+    let mut print = |message: io::Result<ArchivedMessage>| -> io::Result<()> {
+        let message = message?;
+        match message.route(&router, &records) {
+            Ok(job) => writeln!(
+                out,
+                "{} -> {}",
+                message.topic,
+                job.destination.or(&defaults).location(&job.path)
+            )?,
+            Err(letter) => writeln!(
+                out,
+                "{} -> dead letter ({}{})",
+                message.topic,
+                letter.reason,
+                letter
+                    .error
+                    .map(|error| format!(": {error}"))
+                    .unwrap_or_default()
+            )?,
+        }
+        Ok(())
+    };
+
+    if files.is_empty() {
+        replay::read_messages(io::stdin().lock(), "standard input").try_for_each(&mut print)?;
+    }
+    for file in files {
+        replay::read_file(file, config.mqtt.version)?.try_for_each(&mut print)?;
+    }
+    Ok(())
+}
 
-    // Initiate MQTT client on it's own thread and send messages through a channel.
-    let mqtt_thread: JoinHandle<()> = start_mqtt_thread(transmitter);
+/// Route the messages archived below `dir` and write them to the sink.
+async fn replay(
+    config: &BridgeConfig,
+    dir: &Path,
+    options: ReplayOptions,
+) -> azure_core::error::Result<()> {
+    let router = config.mqtt.router()?;
+    let records = config.record_format()?;
+    let write_options = config.write_options()?;
+    let files = replay::archive_files(dir)?;
+    log::info!("Replaying {} file(s) from '{}'", files.len(), dir.display());
 
-    // Create client to interact with the datalake.
-    let data_lake_client = create_data_lake_client().await?;
+    let sink = create_sink(config).await?;
+    check_containers(
+        sink.as_ref(),
+        containers(&router, &write_options)
+            .iter()
+            .map(String::as_str),
+        config.adls.create_container,
+    )
+    .await?;
 
-    // Handle messages received from the MQTT client.
-    handle_write_jobs(data_lake_client, receiver).await?;
+    // Read and route on a blocking thread, while the write loop batches.
+    // Reading waits whenever the channel is full.
+    let (transmitter, receiver) = mpsc::channel(config.queue_capacity);
+    let reader: task::JoinHandle<io::Result<ReplayStats>> = task::spawn_blocking(move || {
+        replay::replay_files(&files, &router, &records, &options, &transmitter)
+    });
 
-    // Wait for the MQTT client to finish.
-    mqtt_thread.join().unwrap();
+    let result = handle_write_jobs(sink.as_ref(), receiver, None, &write_options).await;
+    let stats = reader
+        .await
+        .map_err(|e| Error::full(ErrorKind::Other, e, "Reading the files failed"))??;
+    result?;
+    log::info!(
+        "Replayed {} message(s) from {} file(s). Skipped {} outside the time range and {} that could not be routed",
+        stats.routed,
+        stats.files,
+        stats.filtered,
+        stats.unroutable
+    );
     Ok(())
 }
@@ -0,0 +1,764 @@
+//! Configuration of the bridge.
+//!
+//! Settings are read from an optional TOML or YAML file and then overridden
+//! by environment variables, e.g. `MQTT_BROKER` overrides `mqtt.broker`.
+//! Loading collects every problem it finds, so a broken configuration is
+//! reported in full at startup instead of one error per restart.
+
+use crate::{
+    adls::{Destination, WriteOptions},
+    auth::{AdlsAuth, AuthMode},
+    dead_letter::DeadLetterTarget,
+    mqtt::{parse_topics, MqttConnectOptions, MqttVersion, Subscriptions},
+    record::{Envelope, RecordFormat},
+    retry::RetryPolicy,
+    routing::Router,
+    sink::SinkKind,
+    wal::WriteAheadLog,
+};
+use azure_core::error::{Error, ErrorKind, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    env, fmt, fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Replaces secrets in the printed configuration.
+const MASK: &str = "********";
+
+/// Everything the bridge can be configured with.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BridgeConfig {
+    /// Log filter, e.g. `info` or `mqtt_adls_bridge=debug`.
+    pub log_level: String,
+    /// Where files are written.
+    pub sink: SinkKind,
+    /// The directory files are written to by the `local` sink.
+    pub local_sink_dir: String,
+    /// Seconds before a partial batch is written. `0` disables it.
+    pub flush_interval_secs: u64,
+    /// Messages held between the MQTT client and the write loop.
+    pub queue_capacity: usize,
+    /// Batches uploaded at the same time at most.
+    pub upload_concurrency: usize,
+    pub mqtt: MqttConfig,
+    pub adls: AdlsConfig,
+    pub azure: AzureConfig,
+    pub envelope: EnvelopeConfig,
+    pub retry: RetryConfig,
+    pub dead_letter: DeadLetterConfig,
+    pub wal: WalConfig,
+    pub http: HttpConfig,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> BridgeConfig {
+        BridgeConfig {
+            log_level: "info".to_string(),
+            sink: SinkKind::default(),
+            local_sink_dir: "data".to_string(),
+            flush_interval_secs: 60,
+            queue_capacity: 1000,
+            upload_concurrency: 4,
+            mqtt: MqttConfig::default(),
+            adls: AdlsConfig::default(),
+            azure: AzureConfig::default(),
+            envelope: EnvelopeConfig::default(),
+            retry: RetryConfig::default(),
+            dead_letter: DeadLetterConfig::default(),
+            wal: WalConfig::default(),
+            http: HttpConfig::default(),
+        }
+    }
+}
+
+/// The broker, the session and what is subscribed to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MqttConfig {
+    pub broker: String,
+    pub client_id: String,
+    /// Topic filters, each with an optional QoS, e.g. `packml/#:2`.
+    pub topics: Vec<String>,
+    pub version: MqttVersion,
+    /// Field that MQTT 5 message properties are added to. Empty leaves them out.
+    pub properties_field: String,
+    pub persistent_session: bool,
+    pub session_expiry_secs: u32,
+    pub subscribe_attempts: u32,
+    pub lwt_topic: String,
+    /// Defaults to `Last will for {client_id}`.
+    pub lwt_payload: Option<String>,
+    pub username: String,
+    pub password: String,
+    pub tls_ca_file: String,
+    pub tls_client_cert: String,
+    pub tls_client_key: String,
+    pub tls_key_password: String,
+    pub tls_verify_hostname: bool,
+    /// A YAML file with routing rules. Empty uses the built-in rules.
+    pub routes_file: String,
+}
+
+impl Default for MqttConfig {
+    fn default() -> MqttConfig {
+        MqttConfig {
+            broker: "tcp://localhost:1883".to_string(),
+            client_id: crate::mqtt::DEFAULT_CLIENT_ID.to_string(),
+            topics: vec!["#".to_string()],
+            version: MqttVersion::default(),
+            properties_field: String::new(),
+            persistent_session: false,
+            session_expiry_secs: 86400,
+            subscribe_attempts: 3,
+            lwt_topic: "lwt".to_string(),
+            lwt_payload: None,
+            username: String::new(),
+            password: String::new(),
+            tls_ca_file: String::new(),
+            tls_client_cert: String::new(),
+            tls_client_key: String::new(),
+            tls_key_password: String::new(),
+            tls_verify_hostname: true,
+            routes_file: String::new(),
+        }
+    }
+}
+
+impl MqttConfig {
+    /// The options the MQTT client connects with.
+    pub fn connect_options(&self) -> MqttConnectOptions {
+        MqttConnectOptions {
+            broker: self.broker.clone(),
+            client_id: self.client_id.clone(),
+            username: self.username.clone(),
+            password: self.password.clone(),
+            lwt_topic: self.lwt_topic.clone(),
+            lwt_payload: self
+                .lwt_payload
+                .clone()
+                .unwrap_or_else(|| format!("Last will for {}", self.client_id)),
+            mqtt_version: self.version.code(),
+            persistent_session: self.persistent_session,
+            session_expiry_secs: self.session_expiry_secs,
+            tls_ca_file: self.tls_ca_file.clone(),
+            tls_client_cert: self.tls_client_cert.clone(),
+            tls_client_key: self.tls_client_key.clone(),
+            tls_key_password: self.tls_key_password.clone(),
+            tls_verify_hostname: self.tls_verify_hostname,
+        }
+    }
+
+    /// The topics to subscribe to, each with its QoS.
+    pub fn subscriptions(&self) -> Result<Subscriptions> {
+        Ok(Subscriptions {
+            topics: parse_topics(&self.topics.join(","))?,
+            max_attempts: self.subscribe_attempts.max(1),
+        })
+    }
+
+    /// The routes in `routes_file`, or the built-in ones.
+    pub fn router(&self) -> io::Result<Router> {
+        Router::load(&self.routes_file)
+    }
+}
+
+/// The storage account and where in it files are written.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdlsConfig {
+    pub account_name: String,
+    pub auth: AuthMode,
+    /// Used with `auth = "shared_key"`.
+    pub account_key: String,
+    /// Used with `auth = "sas"`.
+    pub sas_token: String,
+    pub container: String,
+    pub prefix: String,
+    /// Without the leading dot. Empty derives it from the format.
+    pub file_extension: String,
+    pub create_container: bool,
+}
+
+impl Default for AdlsConfig {
+    fn default() -> AdlsConfig {
+        AdlsConfig {
+            account_name: String::new(),
+            auth: AuthMode::default(),
+            account_key: String::new(),
+            sas_token: String::new(),
+            container: "raw".to_string(),
+            prefix: String::new(),
+            file_extension: String::new(),
+            create_container: false,
+        }
+    }
+}
+
+impl AdlsConfig {
+    /// Where files go unless a route says otherwise.
+    pub fn destination(&self) -> Result<Destination> {
+        let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_string());
+        let destination = Destination {
+            container: Some(self.container.clone()),
+            prefix: non_empty(&self.prefix),
+            extension: non_empty(&self.file_extension),
+        };
+        destination
+            .validate()
+            .map_err(|e| Error::with_message(ErrorKind::Other, || e))?;
+        Ok(destination)
+    }
+}
+
+/// Azure AD credentials, used by every `auth` mode except `shared_key` and `sas`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AzureConfig {
+    pub tenant_id: String,
+    /// Application (client) ID, or a user-assigned managed identity.
+    pub client_id: String,
+    pub client_secret: String,
+    /// PEM (certificate and key) or PFX file.
+    pub client_certificate_path: String,
+    pub client_certificate_password: String,
+    pub federated_token_file: String,
+    pub authority_host: String,
+}
+
+impl Default for AzureConfig {
+    fn default() -> AzureConfig {
+        AzureConfig {
+            tenant_id: String::new(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            client_certificate_path: String::new(),
+            client_certificate_password: String::new(),
+            federated_token_file: String::new(),
+            authority_host: "https://login.microsoftonline.com".to_string(),
+        }
+    }
+}
+
+/// Whether records are wrapped in an envelope, and the names of its fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvelopeConfig {
+    pub enabled: bool,
+    pub topic_field: String,
+    pub received_at_field: String,
+    pub qos_field: String,
+    pub retained_field: String,
+    pub payload_field: String,
+}
+
+impl Default for EnvelopeConfig {
+    fn default() -> EnvelopeConfig {
+        let fields = Envelope::default();
+        EnvelopeConfig {
+            enabled: false,
+            topic_field: fields.topic,
+            received_at_field: fields.received_at,
+            qos_field: fields.qos,
+            retained_field: fields.retained,
+            payload_field: fields.payload,
+        }
+    }
+}
+
+/// How failed uploads are retried.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_attempts: u32,
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 5,
+            backoff_ms: 500,
+            max_backoff_ms: 30000,
+        }
+    }
+}
+
+/// Where messages and batches go that can't be handled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    /// Path that unparseable and unroutable messages are written to.
+    pub path: String,
+    /// Topic that unparseable and unroutable messages are published to.
+    /// Takes precedence over `path`.
+    pub topic: String,
+    /// Dead letters written to each file below `path`. Files of fewer are
+    /// written after the flush interval.
+    pub n_per_file: i32,
+    /// Local directory for batches that could not be uploaded.
+    pub dir: String,
+}
+
+impl Default for DeadLetterConfig {
+    fn default() -> DeadLetterConfig {
+        DeadLetterConfig {
+            path: "dead-letter".to_string(),
+            topic: String::new(),
+            n_per_file: 100,
+            dir: "dead-letter".to_string(),
+        }
+    }
+}
+
+/// The optional write-ahead log.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalConfig {
+    /// Empty disables the log.
+    pub dir: String,
+}
+
+/// The metrics and health endpoints.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Empty disables the endpoints.
+    pub addr: String,
+    /// Seconds without progress before `/healthz` fails.
+    pub max_stall_secs: u64,
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig {
+            addr: "0.0.0.0:9090".to_string(),
+            max_stall_secs: 300,
+        }
+    }
+}
+
+/// Every problem found while loading the configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl BridgeConfig {
+    /// Load `file`, if given, override it with the environment and validate
+    /// the result.
+    pub fn load(file: Option<&Path>) -> std::result::Result<BridgeConfig, ConfigError> {
+        BridgeConfig::load_with(file, |key| env::var(key).ok())
+    }
+
+    /// Like `load`, but reads variables through `var` instead of from the
+    /// environment.
+    pub fn load_with<F>(
+        file: Option<&Path>,
+        var: F,
+    ) -> std::result::Result<BridgeConfig, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut problems = Vec::new();
+        let mut config = match file.map(BridgeConfig::from_file) {
+            Some(Ok(config)) => config,
+            Some(Err(e)) => {
+                problems.push(e);
+                BridgeConfig::default()
+            }
+            None => BridgeConfig::default(),
+        };
+        let file_ok = problems.is_empty();
+
+        let mut overrides = Overrides {
+            var: &var,
+            problems: Vec::new(),
+        };
+        config.override_from(&mut overrides);
+        problems.append(&mut overrides.problems);
+
+        // Without the file, validation would add follow-up problems, such as
+        // missing credentials, that only hide the actual one. A variable that
+        // can't be parsed leaves its setting as it was, so the rest can still
+        // be checked.
+        if file_ok {
+            problems.append(&mut config.validate());
+        }
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+
+    /// Read a TOML file, or a YAML file if it ends in `.yaml` or `.yml`.
+    pub fn from_file(file: &Path) -> std::result::Result<BridgeConfig, String> {
+        let content = fs::read_to_string(file)
+            .map_err(|e| format!("Error reading '{}': {}", file.display(), e))?;
+        let yaml = matches!(
+            file.extension().and_then(|ext| ext.to_str()),
+            Some("yaml" | "yml")
+        );
+        let parsed = if yaml {
+            serde_yaml::from_str(&content).map_err(|e| e.to_string())
+        } else {
+            toml::from_str(&content).map_err(|e| e.to_string())
+        };
+        parsed.map_err(|e| format!("Invalid '{}': {}", file.display(), e))
+    }
+
+    /// Apply the environment variables that are set.
+    fn override_from(&mut self, env: &mut Overrides) {
+        env.parse("RUST_LOG", &mut self.log_level);
+        env.parse("SINK", &mut self.sink);
+        env.parse("LOCAL_SINK_DIR", &mut self.local_sink_dir);
+        env.parse("FLUSH_INTERVAL_SECS", &mut self.flush_interval_secs);
+        env.parse("QUEUE_CAPACITY", &mut self.queue_capacity);
+        env.parse("UPLOAD_CONCURRENCY", &mut self.upload_concurrency);
+
+        let mqtt = &mut self.mqtt;
+        env.parse("MQTT_BROKER", &mut mqtt.broker);
+        env.parse("MQTT_CLIENT_ID", &mut mqtt.client_id);
+        env.list("MQTT_TOPICS", &mut mqtt.topics);
+        env.parse("MQTT_VERSION", &mut mqtt.version);
+        env.parse("MQTT_PROPERTIES_FIELD", &mut mqtt.properties_field);
+        env.flag("MQTT_PERSISTENT_SESSION", &mut mqtt.persistent_session);
+        env.parse("MQTT_SESSION_EXPIRY_SECS", &mut mqtt.session_expiry_secs);
+        env.parse("MQTT_SUBSCRIBE_ATTEMPTS", &mut mqtt.subscribe_attempts);
+        env.parse("MQTT_LWT_TOPIC", &mut mqtt.lwt_topic);
+        env.optional("MQTT_LWT_PAYLOAD", &mut mqtt.lwt_payload);
+        env.parse("MQTT_USERNAME", &mut mqtt.username);
+        env.parse("MQTT_PASSWORD", &mut mqtt.password);
+        env.parse("MQTT_TLS_CA_FILE", &mut mqtt.tls_ca_file);
+        env.parse("MQTT_TLS_CLIENT_CERT", &mut mqtt.tls_client_cert);
+        env.parse("MQTT_TLS_CLIENT_KEY", &mut mqtt.tls_client_key);
+        env.parse("MQTT_TLS_KEY_PASSWORD", &mut mqtt.tls_key_password);
+        env.flag("MQTT_TLS_VERIFY_HOSTNAME", &mut mqtt.tls_verify_hostname);
+        env.parse("MQTT_ROUTES_FILE", &mut mqtt.routes_file);
+
+        let adls = &mut self.adls;
+        env.parse("ADLSGEN2_STORAGE_ACCOUNT_NAME", &mut adls.account_name);
+        env.parse("ADLS_AUTH", &mut adls.auth);
+        env.parse("ADLSGEN2_STORAGE_ACCOUNT_KEY", &mut adls.account_key);
+        env.parse("ADLSGEN2_SAS_TOKEN", &mut adls.sas_token);
+        env.parse("ADLS_CONTAINER", &mut adls.container);
+        env.parse("ADLS_PREFIX", &mut adls.prefix);
+        env.parse("ADLS_FILE_EXTENSION", &mut adls.file_extension);
+        env.flag("ADLS_CREATE_CONTAINER", &mut adls.create_container);
+
+        let azure = &mut self.azure;
+        env.parse("AZURE_TENANT_ID", &mut azure.tenant_id);
+        env.parse("AZURE_CLIENT_ID", &mut azure.client_id);
+        env.parse("AZURE_CLIENT_SECRET", &mut azure.client_secret);
+        env.parse(
+            "AZURE_CLIENT_CERTIFICATE_PATH",
+            &mut azure.client_certificate_path,
+        );
+        env.parse(
+            "AZURE_CLIENT_CERTIFICATE_PASSWORD",
+            &mut azure.client_certificate_password,
+        );
+        env.parse(
+            "AZURE_FEDERATED_TOKEN_FILE",
+            &mut azure.federated_token_file,
+        );
+        env.parse("AZURE_AUTHORITY_HOST", &mut azure.authority_host);
+
+        let envelope = &mut self.envelope;
+        env.flag("ENVELOPE", &mut envelope.enabled);
+        env.parse("ENVELOPE_TOPIC_FIELD", &mut envelope.topic_field);
+        env.parse(
+            "ENVELOPE_RECEIVED_AT_FIELD",
+            &mut envelope.received_at_field,
+        );
+        env.parse("ENVELOPE_QOS_FIELD", &mut envelope.qos_field);
+        env.parse("ENVELOPE_RETAINED_FIELD", &mut envelope.retained_field);
+        env.parse("ENVELOPE_PAYLOAD_FIELD", &mut envelope.payload_field);
+
+        env.parse("RETRY_MAX_ATTEMPTS", &mut self.retry.max_attempts);
+        env.parse("RETRY_BACKOFF_MS", &mut self.retry.backoff_ms);
+        env.parse("RETRY_MAX_BACKOFF_MS", &mut self.retry.max_backoff_ms);
+
+        env.parse("DEAD_LETTER_PATH", &mut self.dead_letter.path);
+        env.parse("DEAD_LETTER_TOPIC", &mut self.dead_letter.topic);
+        env.parse("DEAD_LETTER_N_PER_FILE", &mut self.dead_letter.n_per_file);
+        env.parse("DEAD_LETTER_DIR", &mut self.dead_letter.dir);
+
+        env.parse("WAL_DIR", &mut self.wal.dir);
+
+        env.parse("HTTP_ADDR", &mut self.http.addr);
+        env.parse("HEALTH_MAX_STALL_SECS", &mut self.http.max_stall_secs);
+    }
+
+    /// Check every setting, returning all problems found.
+    ///
+    /// Routes are loaded and credentials checked for completeness, but
+    /// nothing is connected to.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut check = |result: Result<()>| {
+            if let Err(e) = result {
+                problems.push(e.to_string());
+            }
+        };
+
+        check(self.mqtt.subscriptions().map(drop));
+        check(self.mqtt.router().map(drop).map_err(Error::from));
+        check(self.record_format().map(drop));
+        check(self.adls.destination().map(drop));
+        if let (Ok(router), Ok(defaults)) = (self.mqtt.router(), self.adls.destination()) {
+            check(router.check_locations(&defaults).map_err(Error::from));
+        }
+        check(self.http_addr().map(drop));
+
+        let connect_options = self.mqtt.connect_options();
+        if connect_options.uses_tls() {
+            check(connect_options.ssl_options().map(drop));
+        }
+        if self.queue_capacity == 0 {
+            check(Err(invalid("QUEUE_CAPACITY must be at least 1")));
+        }
+        if self.upload_concurrency == 0 {
+            check(Err(invalid("UPLOAD_CONCURRENCY must be at least 1")));
+        }
+        if self.dead_letter.n_per_file < 1 {
+            check(Err(invalid("DEAD_LETTER_N_PER_FILE must be at least 1")));
+        }
+        // The client library acknowledges a message as soon as the message
+        // callback returns. A persistent session therefore relies on the
+        // write-ahead log to keep messages that are acknowledged but not yet
+        // uploaded.
+        if self.mqtt.persistent_session && self.wal.dir.is_empty() {
+            check(Err(invalid(
+                "MQTT_PERSISTENT_SESSION requires WAL_DIR to be set",
+            )));
+        }
+
+        if self.sink == SinkKind::Adls {
+            if self.adls.account_name.is_empty() {
+                check(Err(invalid("ADLSGEN2_STORAGE_ACCOUNT_NAME must be set")));
+            }
+            check(self.adls_auth().map(drop));
+        }
+        problems
+    }
+
+    /// How received messages are turned into records.
+    pub fn record_format(&self) -> Result<RecordFormat> {
+        let envelope = &self.envelope;
+        let format = RecordFormat {
+            envelope: envelope.enabled.then(|| Envelope {
+                topic: envelope.topic_field.clone(),
+                received_at: envelope.received_at_field.clone(),
+                qos: envelope.qos_field.clone(),
+                retained: envelope.retained_field.clone(),
+                payload: envelope.payload_field.clone(),
+            }),
+            properties_field: self.mqtt.properties_field.clone(),
+        };
+        format.validate()?;
+        Ok(format)
+    }
+
+    /// How the write loop batches, retries and dead-letters.
+    pub fn write_options(&self) -> Result<WriteOptions> {
+        let secs = self.flush_interval_secs;
+        Ok(WriteOptions {
+            defaults: self.adls.destination()?,
+            flush_interval: (secs > 0).then(|| Duration::from_secs(secs)),
+            retry: RetryPolicy {
+                max_attempts: self.retry.max_attempts.max(1),
+                initial_backoff: Duration::from_millis(self.retry.backoff_ms),
+                max_backoff: Duration::from_millis(self.retry.max_backoff_ms),
+            },
+            dead_letter_dir: PathBuf::from(&self.dead_letter.dir),
+            concurrency: self.upload_concurrency.max(1),
+        })
+    }
+
+    /// Where unparseable and unroutable messages go.
+    pub fn dead_letter_target(&self) -> DeadLetterTarget {
+        DeadLetterTarget::new(
+            &self.dead_letter.topic,
+            &self.dead_letter.path,
+            self.dead_letter.n_per_file,
+        )
+    }
+
+    /// Open the write-ahead log, or return `None` if it is disabled.
+    pub fn wal(&self) -> io::Result<Option<WriteAheadLog>> {
+        if self.wal.dir.is_empty() {
+            return Ok(None);
+        }
+        log::info!("Using write-ahead log in '{}'", self.wal.dir);
+        WriteAheadLog::open(&self.wal.dir).map(Some)
+    }
+
+    /// The address of the metrics and health endpoints, or `None` if they
+    /// are disabled.
+    pub fn http_addr(&self) -> Result<Option<SocketAddr>> {
+        if self.http.addr.is_empty() {
+            return Ok(None);
+        }
+        self.http
+            .addr
+            .parse()
+            .map(Some)
+            .map_err(|e| Error::full(ErrorKind::DataConversion, e, "Invalid HTTP_ADDR"))
+    }
+
+    /// The credentials of the selected `auth` mode.
+    pub fn adls_auth(&self) -> Result<AdlsAuth> {
+        let mode = self.adls.auth;
+        let required = |key: &str, value: &str| {
+            if value.is_empty() {
+                return Err(Error::with_message(ErrorKind::Credential, || {
+                    format!("{key} must be set for ADLS_AUTH={}", mode.as_str())
+                }));
+            }
+            Ok(value.to_string())
+        };
+        let azure = &self.azure;
+        let authority_host = azure.authority_host.clone();
+        let auth = match mode {
+            AuthMode::SharedKey => AdlsAuth::SharedKey {
+                account_key: required("ADLSGEN2_STORAGE_ACCOUNT_KEY", &self.adls.account_key)?,
+            },
+            AuthMode::Sas => AdlsAuth::Sas {
+                token: required("ADLSGEN2_SAS_TOKEN", &self.adls.sas_token)?,
+            },
+            AuthMode::ClientSecret => AdlsAuth::ClientSecret {
+                authority_host,
+                tenant_id: required("AZURE_TENANT_ID", &azure.tenant_id)?,
+                client_id: required("AZURE_CLIENT_ID", &azure.client_id)?,
+                client_secret: required("AZURE_CLIENT_SECRET", &azure.client_secret)?,
+            },
+            AuthMode::ClientCertificate => AdlsAuth::ClientCertificate {
+                authority_host,
+                tenant_id: required("AZURE_TENANT_ID", &azure.tenant_id)?,
+                client_id: required("AZURE_CLIENT_ID", &azure.client_id)?,
+                certificate_path: required(
+                    "AZURE_CLIENT_CERTIFICATE_PATH",
+                    &azure.client_certificate_path,
+                )?,
+                password: azure.client_certificate_password.clone(),
+            },
+            AuthMode::WorkloadIdentity => AdlsAuth::WorkloadIdentity {
+                authority_host,
+                tenant_id: required("AZURE_TENANT_ID", &azure.tenant_id)?,
+                client_id: required("AZURE_CLIENT_ID", &azure.client_id)?,
+                token_file: required("AZURE_FEDERATED_TOKEN_FILE", &azure.federated_token_file)?,
+            },
+            AuthMode::ManagedIdentity => AdlsAuth::ManagedIdentity {
+                client_id: Some(azure.client_id.clone()).filter(|id| !id.is_empty()),
+            },
+        };
+        Ok(auth)
+    }
+
+    /// A copy with every secret that is set replaced by `********`.
+    pub fn masked(&self) -> BridgeConfig {
+        let mut config = self.clone();
+        for secret in [
+            &mut config.mqtt.password,
+            &mut config.mqtt.tls_key_password,
+            &mut config.adls.account_key,
+            &mut config.adls.sas_token,
+            &mut config.azure.client_secret,
+            &mut config.azure.client_certificate_password,
+        ] {
+            if !secret.is_empty() {
+                *secret = MASK.to_string();
+            }
+        }
+        config
+    }
+
+    /// The configuration as a TOML file, e.g. for `validate-config --print`.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("The configuration can be written as TOML")
+    }
+}
+
+fn invalid(message: &'static str) -> Error {
+    Error::message(ErrorKind::Other, message)
+}
+
+/// Parse the name of a variant of `T`, e.g. `local` for `SinkKind::Local`,
+/// the same way the configuration file does.
+pub(crate) fn parse_name<T: DeserializeOwned>(s: &str) -> std::result::Result<T, String> {
+    T::deserialize(serde::de::value::StrDeserializer::<serde::de::value::Error>::new(s))
+        .map_err(|e| e.to_string())
+}
+
+/// Applies environment variables to the configuration and collects the
+/// ones that can't be parsed.
+struct Overrides<'a> {
+    var: &'a dyn Fn(&str) -> Option<String>,
+    problems: Vec<String>,
+}
+
+impl Overrides<'_> {
+    fn parse<T>(&mut self, key: &str, target: &mut T)
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        if let Some(value) = (self.var)(key) {
+            match value.parse() {
+                Ok(value) => *target = value,
+                Err(e) => self.problems.push(format!("Invalid {key} '{value}': {e}")),
+            }
+        }
+    }
+
+    /// A boolean, which may also be written as `1`/`0` or `yes`/`no`.
+    fn flag(&mut self, key: &str, target: &mut bool) {
+        if let Some(value) = (self.var)(key) {
+            match value.to_lowercase().as_str() {
+                "true" | "1" | "yes" => *target = true,
+                "false" | "0" | "no" | "" => *target = false,
+                _ => self
+                    .problems
+                    .push(format!("Invalid {key} '{value}': expected true or false")),
+            }
+        }
+    }
+
+    /// A comma-separated list.
+    fn list(&mut self, key: &str, target: &mut Vec<String>) {
+        if let Some(value) = (self.var)(key) {
+            *target = value
+                .split(',')
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+    }
+
+    /// A string where empty means unset.
+    fn optional(&mut self, key: &str, target: &mut Option<String>) {
+        if let Some(value) = (self.var)(key) {
+            *target = (!value.is_empty()).then_some(value);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use crate::adls::WriteJob;
+use chrono::{DateTime, Utc};
+use paho_mqtt as mqtt;
+use serde::Serialize;
+
+/// Why a message could not be written to a routed path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    /// The payload is not valid JSON.
+    Unparseable,
+    /// No route matches the topic and payload.
+    Unroutable,
+}
+
+impl Reason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Unparseable => "unparseable",
+            Reason::Unroutable => "unroutable",
+        }
+    }
+}
+
+/// A message that could not be routed, with everything needed to reprocess it.
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub received_at: DateTime<Utc>,
+    pub reason: &'static str,
+    /// Details about the failure, e.g. the parse error.
+    pub error: Option<String>,
+    /// The raw payload, base64 encoded.
+    pub payload_base64: String,
+}
+
+impl DeadLetter {
+    /// The dead letter of `msg`, which was received at `received_at`.
+    pub fn new(
+        msg: &mqtt::Message,
+        received_at: DateTime<Utc>,
+        reason: Reason,
+        error: Option<String>,
+    ) -> DeadLetter {
+        DeadLetter {
+            topic: msg.topic().to_string(),
+            received_at,
+            reason: reason.as_str(),
+            error,
+            payload_base64: base64::encode(msg.payload()),
+        }
+    }
+}
+
+/// Where dead letters are sent.
+#[derive(Debug, Clone)]
+pub enum DeadLetterTarget {
+    /// Written through the sink below `path`, partitioned by reason, with
+    /// up to `n_per_file` dead letters in each file.
+    Path { path: String, n_per_file: i32 },
+    /// Published to this MQTT topic.
+    Topic(String),
+    /// Logged and dropped.
+    Drop,
+}
+
+impl DeadLetterTarget {
+    /// Publish to `topic` if it is set, or else write below `path`, in
+    /// files of `n_per_file` dead letters.
+    ///
+    /// If both are empty, dead letters are dropped.
+    pub fn new(topic: &str, path: &str, n_per_file: i32) -> DeadLetterTarget {
+        if !topic.is_empty() {
+            DeadLetterTarget::Topic(topic.to_string())
+        } else if !path.is_empty() {
+            DeadLetterTarget::Path {
+                path: path.to_string(),
+                n_per_file,
+            }
+        } else {
+            DeadLetterTarget::Drop
+        }
+    }
+
+    /// Send `letter` to this target.
+    ///
+    /// For a `Path` target a `WriteJob` is returned, which should be handed
+    /// to the write loop like any other message.
+    pub fn send(&self, cli: &mqtt::AsyncClient, letter: DeadLetter) -> Option<WriteJob> {
+        log::warn!(
+            "Dead-lettering {} message on '{}': {}",
+            letter.reason,
+            letter.topic,
+            letter.error.as_deref().unwrap_or("no matching route")
+        );
+        let record = match serde_json::to_string(&letter) {
+            Ok(record) => record,
+            Err(e) => {
+                log::error!("Error serializing dead letter: {}", e);
+                return None;
+            }
+        };
+
+        match self {
+            DeadLetterTarget::Path { path, n_per_file } => Some(WriteJob {
+                path: format!("{}/reason={}", path, letter.reason),
+                payload: record,
+                n_per_file: *n_per_file,
+                time: Some(letter.received_at),
+                ..Default::default()
+            }),
+            DeadLetterTarget::Topic(topic) => {
+                cli.publish(mqtt::Message::new(topic, record, 1));
+                None
+            }
+            DeadLetterTarget::Drop => None,
+        }
+    }
+
+    /// Whether `topic` is where this target publishes to. Such messages are
+    /// never dead-lettered again, to avoid a loop.
+    pub fn is_own_topic(&self, topic: &str) -> bool {
+        matches!(self, DeadLetterTarget::Topic(t) if t == topic)
+    }
+}
@@ -0,0 +1,170 @@
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// How a time in a payload is written.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub enum TimeFormat {
+    /// E.g. `2022-08-01T12:00:00.123+02:00`. The offset may be left out.
+    #[default]
+    Rfc3339,
+    /// Seconds since the epoch, as a number or a string.
+    Unix,
+    /// Milliseconds since the epoch, as a number or a string.
+    UnixMs,
+    /// A `strftime` pattern, e.g. `%d/%m/%Y %H:%M:%S`.
+    Pattern(String),
+}
+
+impl From<String> for TimeFormat {
+    fn from(s: String) -> TimeFormat {
+        match s.as_str() {
+            "rfc3339" => TimeFormat::Rfc3339,
+            "unix" => TimeFormat::Unix,
+            "unix_ms" => TimeFormat::UnixMs,
+            _ => TimeFormat::Pattern(s),
+        }
+    }
+}
+
+/// Where the time used for the date placeholders of a route comes from.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventTime {
+    /// JSON pointer to the time in the payload, e.g. `/timestamp`, or a
+    /// message property starting with `$`. Without it, or if the payload has
+    /// no valid time, the time the message was received is used.
+    #[serde(default)]
+    pub field: Option<String>,
+    #[serde(default)]
+    pub format: TimeFormat,
+    /// Time zone of the date placeholders, and of payload times without an
+    /// offset, e.g. `Europe/Copenhagen`.
+    #[serde(default = "default_timezone")]
+    pub timezone: Tz,
+}
+
+fn default_timezone() -> Tz {
+    Tz::UTC
+}
+
+impl Default for EventTime {
+    fn default() -> EventTime {
+        EventTime {
+            field: None,
+            format: TimeFormat::default(),
+            timezone: default_timezone(),
+        }
+    }
+}
+
+impl EventTime {
+    /// Check the field and the `strftime` pattern.
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(field) = &self.field {
+            if !field.starts_with('/') && !field.starts_with('$') {
+                return Err(format!("Time field '{field}' must start with '/' or '$'"));
+            }
+        }
+        if let TimeFormat::Pattern(pattern) = &self.format {
+            if StrftimeItems::new(pattern).any(|item| item == Item::Error) {
+                return Err(format!("Invalid time format '{pattern}'"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse `value` according to the format.
+    ///
+    /// Returns `None` if it is not a time in that format.
+    pub fn parse(&self, value: &Value) -> Option<DateTime<Utc>> {
+        match &self.format {
+            TimeFormat::Unix => from_timestamp(value, 1),
+            TimeFormat::UnixMs => from_timestamp(value, 1000),
+            TimeFormat::Rfc3339 => {
+                let s = value.as_str()?;
+                match DateTime::parse_from_rfc3339(s) {
+                    Ok(time) => Some(time.to_utc()),
+                    Err(_) => {
+                        let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S%.f")
+                            .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f"))
+                            .ok()?;
+                        self.localize(naive)
+                    }
+                }
+            }
+            TimeFormat::Pattern(pattern) => {
+                let s = match value {
+                    Value::String(s) => s.to_string(),
+                    other => other.to_string(),
+                };
+                if let Ok(time) = DateTime::parse_from_str(&s, pattern) {
+                    return Some(time.to_utc());
+                }
+                let naive = NaiveDateTime::parse_from_str(&s, pattern)
+                    .ok()
+                    .or_else(|| {
+                        NaiveDate::parse_from_str(&s, pattern)
+                            .ok()
+                            .and_then(|date| date.and_hms_opt(0, 0, 0))
+                    })?;
+                self.localize(naive)
+            }
+        }
+    }
+
+    /// Interpret a time without offset in the time zone. Times that occur
+    /// twice when clocks are turned back take the earlier one.
+    fn localize(&self, naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self.timezone.from_local_datetime(&naive) {
+            LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => Some(time.to_utc()),
+            LocalResult::None => None,
+        }
+    }
+}
+
+/// The time `value` units after the epoch, with `units_per_sec` 1 for
+/// seconds and 1000 for milliseconds.
+///
+/// Integers are converted exactly. Fractions are rounded to the nanosecond.
+/// Both are floored, so times before the epoch count back from the second
+/// after them, e.g. -1.5 seconds is 1969-12-31T23:59:58.5Z.
+fn from_timestamp(value: &Value, units_per_sec: i64) -> Option<DateTime<Utc>> {
+    const NANOS_PER_SEC: i64 = 1_000_000_000;
+    let nanos_per_unit = NANOS_PER_SEC / units_per_sec;
+    let (units, fraction) = match as_i64(value) {
+        Some(units) => (units, 0),
+        None => {
+            let units = as_f64(value).filter(|units| units.is_finite())?;
+            let whole = units.floor();
+            let fraction = ((units - whole) * nanos_per_unit as f64).round() as i64;
+            (whole as i64, fraction)
+        }
+    };
+    // Rounding the fraction up may carry over into the next second.
+    let nanos = units.rem_euclid(units_per_sec) * nanos_per_unit + fraction;
+    let secs = units
+        .div_euclid(units_per_sec)
+        .checked_add(nanos.div_euclid(NANOS_PER_SEC))?;
+    DateTime::from_timestamp(secs, nanos.rem_euclid(NANOS_PER_SEC) as u32)
+}
+
+fn as_i64(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
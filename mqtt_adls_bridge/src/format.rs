@@ -0,0 +1,211 @@
+use arrow_json::reader::{infer_json_schema_from_iterator, ReaderBuilder};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use azure_core::error::{Error, ErrorKind, Result};
+use bytes::Bytes;
+use flate2::{write::GzEncoder, Compression as GzCompression};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression as ParquetCompression, GzipLevel, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{io::Write, sync::Arc};
+
+use crate::sink::FileMetadata;
+
+/// The kind of file a batch is written as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Newline-delimited JSON.
+    #[default]
+    Json,
+    /// Apache Parquet, one file per batch.
+    Parquet,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Parquet => "parquet",
+        }
+    }
+}
+
+/// Compression codec of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Snappy,
+    Gzip,
+    Zstd,
+}
+
+/// Type of a column in a Parquet schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    String,
+    Boolean,
+    Int32,
+    Int64,
+    Float64,
+    /// An RFC 3339 string or a number of milliseconds since the epoch.
+    Timestamp,
+}
+
+impl ColumnType {
+    fn data_type(&self) -> DataType {
+        match self {
+            ColumnType::String => DataType::Utf8,
+            ColumnType::Boolean => DataType::Boolean,
+            ColumnType::Int32 => DataType::Int32,
+            ColumnType::Int64 => DataType::Int64,
+            ColumnType::Float64 => DataType::Float64,
+            ColumnType::Timestamp => {
+                DataType::Timestamp(TimeUnit::Millisecond, Some("+00:00".into()))
+            }
+        }
+    }
+}
+
+/// A column in a Parquet schema.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+}
+
+/// How the payloads of a path are encoded into a file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFormat {
+    #[serde(default)]
+    pub format: Format,
+    /// Defaults to `snappy` for Parquet and no compression for JSON.
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Columns of a Parquet file. Inferred from each batch if not set.
+    #[serde(default)]
+    pub schema: Option<Vec<Column>>,
+}
+
+impl FileFormat {
+    /// Check that the compression is supported by the format.
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        match (self.format, self.compression) {
+            (Format::Json, Some(Compression::Snappy)) => {
+                Err("compression snappy is not supported for json".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The file extension, including the compression for JSON.
+    pub fn extension(&self) -> &'static str {
+        match (self.format, self.compression) {
+            (Format::Json, Some(Compression::Gzip)) => "json.gz",
+            (Format::Json, Some(Compression::Zstd)) => "json.zst",
+            (Format::Json, _) => "json",
+            (Format::Parquet, _) => "parquet",
+        }
+    }
+
+    /// Metadata describing how a file in this format is encoded.
+    ///
+    /// Parquet compresses its pages internally, so the file itself has no
+    /// content encoding.
+    pub fn metadata(&self) -> FileMetadata {
+        match self.format {
+            Format::Json => FileMetadata {
+                content_type: "application/x-ndjson",
+                content_encoding: self.compression.and_then(|c| c.content_encoding()),
+            },
+            Format::Parquet => FileMetadata {
+                content_type: "application/vnd.apache.parquet",
+                content_encoding: None,
+            },
+        }
+    }
+}
+
+impl Compression {
+    /// The HTTP `Content-Encoding` of a file compressed with this codec.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        match self {
+            Compression::Gzip => Some("gzip"),
+            Compression::Zstd => Some("zstd"),
+            Compression::None | Compression::Snappy => None,
+        }
+    }
+}
+
+/// Compress a whole file with `compression`.
+///
+/// Snappy is only supported inside Parquet and leaves `content` unchanged,
+/// like `none`.
+pub fn compress(content: Vec<u8>, compression: Compression) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None | Compression::Snappy => Ok(content),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), GzCompression::default());
+            encoder.write_all(&content)?;
+            Ok(encoder.finish()?)
+        }
+        Compression::Zstd => Ok(zstd::encode_all(content.as_slice(), 0)?),
+    }
+}
+
+/// Encode newline-delimited JSON `lines` as a single Parquet file.
+///
+/// Every line must be a JSON object. Fields that are not in `schema` are
+/// ignored; without a schema, it is inferred from the lines themselves.
+pub fn to_parquet(lines: &[String], format: &FileFormat) -> Result<Bytes> {
+    let values = lines
+        .iter()
+        .map(|line| serde_json::from_str::<Value>(line))
+        .collect::<serde_json::Result<Vec<Value>>>()?;
+
+    let schema = match &format.schema {
+        Some(columns) => Schema::new(
+            columns
+                .iter()
+                .map(|c| Field::new(&c.name, c.column_type.data_type(), true))
+                .collect::<Vec<Field>>(),
+        ),
+        None => infer_json_schema_from_iterator(values.iter().map(Ok)).map_err(conversion)?,
+    };
+    let schema = Arc::new(schema);
+
+    let mut decoder = ReaderBuilder::new(schema.clone())
+        .with_batch_size(values.len().max(1))
+        .build_decoder()
+        .map_err(conversion)?;
+    decoder.serialize(&values).map_err(conversion)?;
+
+    let compression = match format.compression.unwrap_or(Compression::Snappy) {
+        Compression::None => ParquetCompression::UNCOMPRESSED,
+        Compression::Snappy => ParquetCompression::SNAPPY,
+        Compression::Gzip => ParquetCompression::GZIP(GzipLevel::default()),
+        Compression::Zstd => ParquetCompression::ZSTD(ZstdLevel::default()),
+    };
+    let props = WriterProperties::builder()
+        .set_compression(compression)
+        .build();
+
+    let mut writer = ArrowWriter::try_new(Vec::new(), schema, Some(props)).map_err(conversion)?;
+    while let Some(batch) = decoder.flush().map_err(conversion)? {
+        writer.write(&batch).map_err(conversion)?;
+    }
+    Ok(Bytes::from(writer.into_inner().map_err(conversion)?))
+}
+
+fn conversion<E>(e: E) -> Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Error::new(ErrorKind::DataConversion, e)
+}
@@ -0,0 +1,86 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The state reported on `/healthz` and `/readyz`.
+pub static HEALTH: LazyLock<Health> = LazyLock::new(Health::default);
+
+/// When a loop last showed that it is still running.
+#[derive(Debug, Default)]
+pub struct Heartbeat(Mutex<Option<Instant>>);
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Whether the loop has beaten within `max_stall`. A loop that has not
+    /// started yet counts as alive.
+    fn is_alive(&self, max_stall: Duration) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .is_none_or(|last| last.elapsed() <= max_stall)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Health {
+    /// Beaten by the MQTT client's message callback and by its connection
+    /// probe, whenever either completes. The name is kept for the liveness key.
+    pub mqtt_thread: Heartbeat,
+    /// Beaten by the write loop on every message and every finished upload,
+    /// and every second while it waits for messages with no upload running.
+    pub write_loop: Heartbeat,
+    connected: AtomicBool,
+    upload_failed: AtomicBool,
+}
+
+/// Body of the probe responses.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub ok: bool,
+    pub checks: BTreeMap<&'static str, bool>,
+}
+
+impl Status {
+    fn new<const N: usize>(checks: [(&'static str, bool); N]) -> Status {
+        Status {
+            ok: checks.iter().all(|(_, ok)| *ok),
+            checks: BTreeMap::from(checks),
+        }
+    }
+}
+
+impl Health {
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::SeqCst);
+    }
+
+    /// Record the outcome of the most recent upload.
+    pub fn set_upload_succeeded(&self, succeeded: bool) {
+        self.upload_failed.store(!succeeded, Ordering::SeqCst);
+    }
+
+    /// Whether the MQTT client and the write loop are running.
+    pub fn liveness(&self, max_stall: Duration) -> Status {
+        Status::new([
+            ("mqtt_thread", self.mqtt_thread.is_alive(max_stall)),
+            ("write_loop", self.write_loop.is_alive(max_stall)),
+        ])
+    }
+
+    /// Whether the broker is connected and the last upload, if any, succeeded.
+    pub fn readiness(&self) -> Status {
+        Status::new([
+            ("mqtt_connected", self.connected.load(Ordering::SeqCst)),
+            ("last_upload", !self.upload_failed.load(Ordering::SeqCst)),
+        ])
+    }
+}
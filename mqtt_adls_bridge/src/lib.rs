@@ -1,3 +1,17 @@
 pub mod adls;
+pub mod auth;
+pub mod config;
+pub mod dead_letter;
+pub mod event_time;
+pub mod format;
+pub mod health;
+pub mod metrics;
 pub mod mqtt;
+pub mod record;
+pub mod replay;
+pub mod retry;
+pub mod routing;
+pub mod server;
+pub mod sink;
 pub mod utils;
+pub mod wal;
@@ -0,0 +1,157 @@
+use prometheus::{
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+/// The metrics of the bridge, served on `/metrics`.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+const NAMESPACE: &str = "mqtt_adls_bridge";
+
+/// Upper bounds in seconds of the upload duration buckets.
+const UPLOAD_BUCKETS: [f64; 12] = [
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+pub struct Metrics {
+    registry: Registry,
+    /// Messages received from the broker, by the first route matching their
+    /// topic, or `unroutable`.
+    pub messages_received: IntCounterVec,
+    /// Messages routed to a path, by route.
+    pub messages_routed: IntCounterVec,
+    /// Messages that were dead-lettered instead, by reason.
+    pub messages_dropped: IntCounterVec,
+    /// Payloads or batches that could not be parsed or converted, by format.
+    pub parse_failures: IntCounterVec,
+    /// Messages waiting in a batch, by path.
+    pub buffered_messages: IntGaugeVec,
+    /// Files written, by format.
+    pub batches_uploaded: IntCounterVec,
+    /// Bytes written, by format.
+    pub bytes_uploaded: IntCounterVec,
+    /// Batches written to the dead-letter directory after every upload failed.
+    pub batches_dead_lettered: IntCounter,
+    /// Batches that could not be stored at all, neither uploaded nor
+    /// dead-lettered, or whose log entries could not be removed.
+    pub batches_failed: IntCounter,
+    /// Duration of every upload attempt, by format and result.
+    pub upload_duration: HistogramVec,
+    /// Failed requests to ADLS, by operation.
+    pub adls_errors: IntCounterVec,
+    /// Times the connection to the broker was lost.
+    pub mqtt_reconnects: IntCounter,
+    /// 1 while connected to the broker.
+    pub mqtt_connected: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None)
+            .expect("The namespace is valid");
+        let counter = |name: &str, help: &str, label: &str| {
+            register(
+                &registry,
+                IntCounterVec::new(Opts::new(name, help), &[label]),
+            )
+        };
+
+        Metrics {
+            messages_received: counter(
+                "messages_received_total",
+                "Messages received from the broker",
+                "route",
+            ),
+            messages_routed: counter(
+                "messages_routed_total",
+                "Messages routed to a path",
+                "route",
+            ),
+            messages_dropped: counter(
+                "messages_dropped_total",
+                "Messages that were dead-lettered instead of routed",
+                "reason",
+            ),
+            parse_failures: counter(
+                "parse_failures_total",
+                "Payloads that are not JSON and batches that could not be converted",
+                "format",
+            ),
+            buffered_messages: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("buffered_messages", "Messages waiting in a batch"),
+                    &["path"],
+                ),
+            ),
+            batches_uploaded: counter("batches_uploaded_total", "Files written", "format"),
+            bytes_uploaded: counter("bytes_uploaded_total", "Bytes written", "format"),
+            batches_dead_lettered: register(
+                &registry,
+                IntCounter::new(
+                    "batches_dead_lettered_total",
+                    "Batches written to the dead-letter directory after every upload failed",
+                ),
+            ),
+            batches_failed: register(
+                &registry,
+                IntCounter::new(
+                    "batches_failed_total",
+                    "Batches that could neither be uploaded nor dead-lettered",
+                ),
+            ),
+            upload_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("upload_duration_seconds", "Duration of an upload attempt")
+                        .buckets(UPLOAD_BUCKETS.to_vec()),
+                    &["format", "result"],
+                ),
+            ),
+            adls_errors: counter(
+                "adls_errors_total",
+                "Failed requests to Azure Datalake",
+                "operation",
+            ),
+            mqtt_reconnects: register(
+                &registry,
+                IntCounter::new(
+                    "mqtt_reconnects_total",
+                    "Times the connection to the broker was lost",
+                ),
+            ),
+            mqtt_connected: register(
+                &registry,
+                IntGauge::new("mqtt_connected", "1 while connected to the broker"),
+            ),
+            registry,
+        }
+    }
+
+    /// Content type of `render`.
+    pub fn content_type(&self) -> &'static str {
+        prometheus::TEXT_FORMAT
+    }
+
+    /// Render every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Metrics can be encoded");
+        String::from_utf8(buffer).expect("The text format is UTF-8")
+    }
+}
+
+/// Register `collector`, which is only created once with a fixed name.
+fn register<C>(registry: &Registry, collector: prometheus::Result<C>) -> C
+where
+    C: Collector + Clone + 'static,
+{
+    let collector = collector.expect("The metric is valid");
+    registry
+        .register(Box::new(collector.clone()))
+        .expect("The metric is registered once");
+    collector
+}
@@ -1,9 +1,36 @@
-use crate::{adls, utils};
-use chrono::{Datelike, Utc};
-use dotenv::dotenv;
+use crate::{
+    adls,
+    config::MqttConfig,
+    dead_letter::{DeadLetter, DeadLetterTarget, Reason},
+    health::HEALTH,
+    metrics::METRICS,
+    record::{Received, RecordFormat},
+    routing::{self, MessageProperties, Router},
+    wal::WriteAheadLog,
+};
+use azure_core::error::{Error, ErrorKind};
+use chrono::{DateTime, Utc};
 use paho_mqtt as mqtt;
-use serde_json::{Result, Value};
-use std::{process, sync::mpsc::Sender, thread, thread::JoinHandle, time::Duration};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{
+    fmt,
+    path::Path,
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc::Sender, watch},
+    time::{interval, timeout},
+};
+
+pub const DEFAULT_CLIENT_ID: &str = "rust_client";
 
 /// Connection options for MQTT Client.
 #[derive(Debug)]
@@ -14,203 +41,710 @@ pub struct MqttConnectOptions {
     pub password: String,
     pub lwt_topic: String,
     pub lwt_payload: String,
+    /// Protocol version, either `mqtt::MQTT_VERSION_3_1_1` or `mqtt::MQTT_VERSION_5`.
+    pub mqtt_version: u32,
+    /// Keep the session, i.e. subscriptions and unacknowledged messages, on
+    /// the broker while the bridge is disconnected.
+    pub persistent_session: bool,
+    /// How long an MQTT 5 broker keeps a persistent session after a disconnect.
+    pub session_expiry_secs: u32,
+    /// CA bundle used to verify the broker. Empty uses the system store.
+    pub tls_ca_file: String,
+    /// Client certificate for mutual TLS, optionally including the key.
+    pub tls_client_cert: String,
+    pub tls_client_key: String,
+    pub tls_key_password: String,
+    /// Whether the broker's hostname must match its certificate.
+    pub tls_verify_hostname: bool,
 }
 
 impl Default for MqttConnectOptions {
     fn default() -> MqttConnectOptions {
-        dotenv().ok();
+        MqttConfig::default().connect_options()
+    }
+}
 
-        let broker = utils::env_default("MQTT_BROKER", "tcp://localhost:1883");
-        let client_id = utils::env_default("MQTT_CLIENT_ID", "rust_client");
-        let username = utils::env_default("MQTT_USERNAME", "");
-        let password = utils::env_default("MQTT_PASSWORD", "");
-        let lwt_topic = utils::env_default("MQTT_LWT_TOPIC", "lwt");
-        let lwt_payload_default = format!("Last will for {}", client_id);
-        let lwt_payload = utils::env_default("MQTT_LWT_PAYLOAD", lwt_payload_default.as_str());
+/// The MQTT protocol version, written as `3.1.1` or `5`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MqttVersion {
+    #[default]
+    V3_1_1,
+    V5,
+}
 
-        MqttConnectOptions {
-            broker,
-            client_id,
-            username,
-            password,
-            lwt_topic,
-            lwt_payload,
+impl MqttVersion {
+    /// The version as the client library numbers it.
+    pub fn code(&self) -> u32 {
+        match self {
+            MqttVersion::V3_1_1 => mqtt::MQTT_VERSION_3_1_1,
+            MqttVersion::V5 => mqtt::MQTT_VERSION_5,
         }
     }
 }
 
-/// Contruct MqttPayload based on Topic.
-///
-/// Takes an `mqtt:Message` and constructs a `MqttPayload` based on the topic
-/// from which the `mqtt::Message` is sent.
-fn get_payload(msg: &mqtt::Message) -> Result<adls::WriteJob> {
-    // Get current time
-    let now = Utc::now();
-
-    // Get the message payload and topic
-    let payload: Value = serde_json::from_str(&msg.payload_str())?;
-    let topic = msg.topic();
-    // Get the payload as a String
-    let payload_str: Value = serde_json::from_str(&msg.payload_str()).expect("Hello");
-    // Set default path
-    let mut path = String::from("");
-    // Default for number of files to write at a time.
-    let mut n_per_file = 1;
-
-    // Handle PackML
-    if topic.starts_with("packml") {
-        let machine_idx = &payload["machineIDx"];
-        let telegram_type = &payload["telegramTypeFriendly"];
-        let telegram_version = &payload["telegramTypeVersion"];
-        let service_name = &payload["ServiceName"];
-
-        if topic.contains("event")
-            && telegram_type != &Value::Null
-            && machine_idx != &Value::Null
-            && telegram_version != &Value::Null
-        {
-            path = format!(
-                "packml/event/telegram_type={}/telegram_version={}/machine_idx={}/year={}/month={}/day={}",
-                utils::value_to_string(&telegram_type),
-                utils::value_to_string(&telegram_version),
-                utils::value_to_string(&machine_idx),
-                now.year(),
-                now.month(),
-                now.day()
-            );
-            n_per_file = 10;
-            log::debug!("packml.contains('event') {n_per_file} files per path {path}");
-        } else if topic.contains("status") && service_name != &Value::Null {
-            path = format!(
-                "packml/status/service_name={}",
-                utils::value_to_string(&service_name),
-            );
-            log::debug!("packml.contains('status') {n_per_file} files per path {path}");
+impl fmt::Display for MqttVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttVersion::V3_1_1 => write!(f, "3.1.1"),
+            MqttVersion::V5 => write!(f, "5"),
+        }
+    }
+}
+
+impl FromStr for MqttVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MqttVersion, String> {
+        match s {
+            "3.1.1" | "3" => Ok(MqttVersion::V3_1_1),
+            "5" | "5.0" => Ok(MqttVersion::V5),
+            other => Err(format!(
+                "Unsupported MQTT version '{other}'. Expected '3.1.1' or '5'"
+            )),
+        }
+    }
+}
+
+impl Serialize for MqttVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MqttVersion {
+    /// Accepts numbers as well, since `version = 5` is easy to write.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MqttVersion, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Text(String),
+            Number(f64),
+        }
+        let text = match Raw::deserialize(deserializer)? {
+            Raw::Text(text) => text,
+            Raw::Number(n) => n.to_string(),
+        };
+        text.parse().map_err(de::Error::custom)
+    }
+}
+
+impl MqttConnectOptions {
+    pub fn is_v5(&self) -> bool {
+        self.mqtt_version >= mqtt::MQTT_VERSION_5
+    }
+
+    /// Whether the broker URI asks for a TLS connection.
+    pub fn uses_tls(&self) -> bool {
+        ["ssl://", "mqtts://", "wss://"]
+            .iter()
+            .any(|scheme| self.broker.starts_with(scheme))
+    }
+
+    /// Build the TLS options from the `MQTT_TLS_*` settings.
+    ///
+    /// The broker's certificate is always verified. Fails if one of the
+    /// configured files does not exist.
+    pub fn ssl_options(&self) -> azure_core::error::Result<mqtt::SslOptions> {
+        let mut builder = mqtt::SslOptionsBuilder::new();
+        builder
+            .enable_server_cert_auth(true)
+            .verify(self.tls_verify_hostname);
+        if let Some(ca_file) = tls_file("MQTT_TLS_CA_FILE", &self.tls_ca_file)? {
+            builder.trust_store(ca_file).map_err(tls_error)?;
+        }
+        if let Some(cert) = tls_file("MQTT_TLS_CLIENT_CERT", &self.tls_client_cert)? {
+            builder.key_store(cert).map_err(tls_error)?;
         }
-    } else if topic.starts_with("service") {
-        let host = &payload["Host"];
-        if topic.contains("status") && host != &Value::Null {
-            path = format!("master/status/host={}", utils::value_to_string(&host));
-            log::debug!("service.contains('status') {n_per_file} files per path {path}");
+        if let Some(key) = tls_file("MQTT_TLS_CLIENT_KEY", &self.tls_client_key)? {
+            builder.private_key(key).map_err(tls_error)?;
         }
+        if !self.tls_key_password.is_empty() {
+            builder.private_key_password(&self.tls_key_password);
+        }
+        Ok(builder.finalize())
     }
+}
 
-    let payload = adls::WriteJob {
-        path: path,
-        payload: payload_str.to_string(),
-        n_per_file: n_per_file,
-    };
-    log::debug!("{:?}", payload);
+/// Check that the file configured in `key` exists. Returns `None` if unset.
+fn tls_file<'a>(key: &str, path: &'a str) -> azure_core::error::Result<Option<&'a str>> {
+    if path.is_empty() {
+        return Ok(None);
+    }
+    if !Path::new(path).is_file() {
+        return Err(Error::with_message(ErrorKind::Io, || {
+            format!("{key} '{path}' does not exist")
+        }));
+    }
+    Ok(Some(path))
+}
 
-    Ok(payload)
+fn tls_error(e: mqtt::Error) -> Error {
+    Error::full(ErrorKind::Other, e, "Invalid TLS option")
+}
+
+/// Time to wait for the broker to acknowledge a subscription.
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Topic the liveness probe unsubscribes from. The bridge never subscribes
+/// to it, so the probe has no effect besides the round trip.
+const PROBE_TOPIC: &str = "mqtt-adls-bridge/liveness-probe";
+
+/// How often the connection is probed, and how long a probe may take.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A topic filter and the QoS it is subscribed with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub topic: String,
+    pub qos: i32,
+}
+
+impl fmt::Display for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.topic, self.qos)
+    }
+}
+
+/// The topics to subscribe to and how often a rejected subscription is retried.
+#[derive(Debug, Clone)]
+pub struct Subscriptions {
+    pub topics: Vec<Subscription>,
+    /// Attempts before a rejected subscription stops the bridge.
+    pub max_attempts: u32,
+}
+
+/// Parse a comma-separated list of `topic[:qos]`, as in `MQTT_TOPICS`.
+///
+/// The QoS defaults to 1. Shared subscriptions are written as
+/// `$share/{group}/{filter}`.
+pub fn parse_topics(s: &str) -> azure_core::error::Result<Vec<Subscription>> {
+    let invalid = |msg: String| Error::with_message(ErrorKind::DataConversion, || msg);
+
+    let mut topics = Vec::new();
+    for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        // Topics may contain ':', so only a numeric suffix is taken as the QoS.
+        let (topic, qos) = match entry.rsplit_once(':') {
+            Some((topic, qos)) if qos.chars().all(|c| c.is_ascii_digit()) => {
+                let qos = qos
+                    .parse()
+                    .ok()
+                    .filter(|qos| (0..=2).contains(qos))
+                    .ok_or_else(|| {
+                        invalid(format!("Invalid QoS in MQTT_TOPICS entry '{entry}'"))
+                    })?;
+                (topic.trim(), qos)
+            }
+            _ => (entry, 1),
+        };
+        // A shared subscription is `$share/{group}/{filter}`.
+        let filter = match topic.strip_prefix("$share/") {
+            Some(shared) => match shared.split_once('/') {
+                Some((group, filter)) if !group.is_empty() && !group.contains(['+', '#']) => filter,
+                _ => "",
+            },
+            None => topic,
+        };
+        if filter.is_empty() || !routing::is_valid_filter(filter) {
+            return Err(invalid(format!(
+                "Invalid topic filter in MQTT_TOPICS entry '{entry}'"
+            )));
+        }
+        topics.push(Subscription {
+            topic: topic.to_string(),
+            qos,
+        });
+    }
+
+    if topics.is_empty() {
+        return Err(invalid("MQTT_TOPICS contains no topics".to_string()));
+    }
+    Ok(topics)
+}
+
+/// State shared with the client callbacks through its user data.
+struct ClientState {
+    shutdown: watch::Sender<bool>,
+    subscriptions: Subscriptions,
+    /// Set when a subscription was rejected too often.
+    subscribe_failed: AtomicBool,
+}
+
+/// Read the MQTT v5 properties of `msg`. Empty for MQTT 3.1.1.
+fn message_properties(msg: &mqtt::Message) -> MessageProperties {
+    let props = msg.properties();
+    MessageProperties {
+        content_type: props.get_string(mqtt::PropertyCode::ContentType),
+        message_expiry_interval: props
+            .get_int(mqtt::PropertyCode::MessageExpiryInterval)
+            .map(|secs| secs as u32),
+        user_properties: props.user_iter().collect(),
+    }
+}
+
+/// Contruct a `WriteJob` based on Topic.
+///
+/// Takes an `mqtt:Message` and constructs a `WriteJob` from the first route
+/// in `router` that matches the topic from which the `mqtt::Message` is sent.
+/// Messages that are not JSON or match no route are returned as a `DeadLetter`.
+/// The written record is built from the payload according to `records`.
+///
+/// `received_at` fills the date placeholders of routes without an event time.
+/// Live messages use the current time, replayed ones the time they were
+/// originally received.
+pub fn get_payload(
+    router: &Router,
+    msg: &mqtt::Message,
+    records: &RecordFormat,
+    received_at: DateTime<Utc>,
+) -> Result<adls::WriteJob, DeadLetter> {
+    // Get the message payload
+    let payload: Value = serde_json::from_slice(msg.payload()).map_err(|e| {
+        METRICS.parse_failures.with_label_values(&["json"]).inc();
+        DeadLetter::new(msg, received_at, Reason::Unparseable, Some(e.to_string()))
+    })?;
+
+    let properties = message_properties(msg);
+    let mut job = router.write_job(msg.topic(), &payload, &properties, received_at);
+    if !records.is_plain() && !job.path.is_empty() {
+        let received = Received {
+            topic: msg.topic(),
+            qos: msg.qos(),
+            retained: msg.retained(),
+            received_at,
+            properties: &properties,
+        };
+        job.payload = records.record(&received, payload).to_string();
+    }
+    log::debug!("{:?}", job);
+
+    if job.path.is_empty() {
+        return Err(DeadLetter::new(msg, received_at, Reason::Unroutable, None));
+    }
+    Ok(job)
+}
+
+/// The state stored as the user data of the client, so it is available from
+/// within callbacks.
+fn client_state(cli: &mqtt::AsyncClient) -> Option<&ClientState> {
+    cli.user_data()
+        .and_then(|data| data.downcast_ref::<ClientState>())
+}
+
+/// Whether the bridge is shutting down.
+fn is_shutting_down(cli: &mqtt::AsyncClient) -> bool {
+    client_state(cli).is_some_and(|state| *state.shutdown.borrow())
 }
 
 /// Callback for a successful connection to the broker.
 /// We subscribe to the topic(s) we want here.
 fn on_connect_success(cli: &mqtt::AsyncClient, _msgid: u16) {
     log::info!("Connection succeeded");
-    // Subscribe to the desired topic(s).
-    //cli.subscribe_many(TOPICS, vec!(1, 1));
-    // Since we are using subscribe_many, we have to transform the comma-separated
-    // list of topics into a Vector of Strings.
-    let topics: Vec<String> = utils::env_default("MQTT_TOPICS", "#")
-        .split(",")
-        .map(|el| el.trim().to_string())
+    METRICS.mqtt_connected.set(1);
+    HEALTH.set_connected(true);
+    if let Some(state) = client_state(cli) {
+        subscribe(cli, state.subscriptions.topics.clone(), 1);
+    }
+}
+
+/// Subscribe to `topics` and check the QoS granted by the broker.
+///
+/// Every topic is subscribed on its own, since the client library garbles
+/// the reason codes of MQTT v5 subscriptions to several topics at once.
+/// The acknowledgements are awaited on a separate thread, since the callback
+/// thread delivers them. Rejected topics are retried after a delay; once
+/// `max_attempts` is reached the bridge is stopped.
+fn subscribe(cli: &mqtt::AsyncClient, topics: Vec<Subscription>, attempt: u32) {
+    log::info!(
+        "Subscribing to topics (attempt {}): {}",
+        attempt,
+        list(&topics)
+    );
+    let tokens: Vec<mqtt::Token> = topics
+        .iter()
+        .map(|sub| cli.subscribe(sub.topic.as_str(), sub.qos))
         .collect();
 
-    // TODO: Implement customizable QOS
-    // Currently we default to QOS 1 for all topics.
-    let qos: Vec<i32> = vec![1; topics.len()];
+    let cli = cli.clone();
+    thread::spawn(move || {
+        let rejected: Vec<Subscription> = topics
+            .into_iter()
+            .zip(tokens)
+            .filter_map(|(sub, token)| {
+                let rejected = match token.wait_for(SUBSCRIBE_TIMEOUT) {
+                    Ok(rsp) => match rsp.subscribe_response() {
+                        // 0x80 and MQTT v5 reason codes above it are failures.
+                        Some(qos) if qos >= 0x80 => {
+                            log::error!("Broker rejected subscription to {sub}: code {qos:#04x}");
+                            true
+                        }
+                        Some(qos) if qos < sub.qos => {
+                            log::warn!(
+                                "Broker granted QoS {qos} instead of {} for '{}'",
+                                sub.qos,
+                                sub.topic
+                            );
+                            false
+                        }
+                        _ => false,
+                    },
+                    Err(e) => {
+                        log::error!("Broker rejected subscription to {sub}: {e}");
+                        true
+                    }
+                };
+                rejected.then_some(sub)
+            })
+            .collect();
+
+        if rejected.is_empty() || is_shutting_down(&cli) {
+            return;
+        }
+        let Some(state) = client_state(&cli) else {
+            return;
+        };
+        if attempt >= state.subscriptions.max_attempts {
+            log::error!(
+                "Giving up subscribing after {} attempts. Stopping the bridge",
+                attempt
+            );
+            state.subscribe_failed.store(true, Ordering::SeqCst);
+            state.shutdown.send_replace(true);
+            return;
+        }
+        thread::sleep(Duration::from_millis(2500));
+        subscribe(&cli, rejected, attempt + 1);
+    });
+}
 
-    cli.subscribe_many(&topics, &qos);
-    log::info!("Subscribing to topics: {:?}", topics);
-    // TODO: This doesn't yet handle a failed subscription.
+/// Format subscriptions for logging.
+fn list(topics: &[Subscription]) -> String {
+    topics
+        .iter()
+        .map(Subscription::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 /// Callback for a failed attempt to connect to the server.
-/// We simply sleep and then try again.
+/// We simply try again after a delay.
 ///
-/// Note that normally we don't want to do a blocking operation or sleep
-/// from  within a callback. But in this case, we know that the client is
-/// *not* conected, and thus not doing anything important. So we don't worry
-/// too much about stopping its callback thread.
+/// The bridge reports itself as not ready until a connection succeeds.
 fn on_connect_failure(cli: &mqtt::AsyncClient, _msgid: u16, rc: i32) {
     log::warn!("Connection attempt failed with error code {}.\n", rc);
-    thread::sleep(Duration::from_millis(2500));
-    cli.reconnect_with_callbacks(on_connect_success, on_connect_failure);
+    HEALTH.set_connected(false);
+    reconnect_later(cli);
 }
 
-pub fn start_mqtt_thread(tx: Sender<adls::WriteJob>) -> JoinHandle<()> {
-    // Send MQTT client to it's own thread.
-    let handle = thread::spawn(move || {
-        // By default, values are loaded from env. See <MqttConnectOptions>
-        let mqtt_connect_options: MqttConnectOptions = MqttConnectOptions {
-            ..MqttConnectOptions::default()
-        };
+/// Reconnect after a delay, unless the bridge is shutting down.
+///
+/// The reconnect is made from a separate thread. Connecting from within a
+/// callback replaces the connect options the client library is still using
+/// to finish the previous attempt, which corrupts memory with MQTT v5.
+fn reconnect_later(cli: &mqtt::AsyncClient) {
+    if is_shutting_down(cli) {
+        return;
+    }
+    let cli = cli.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(2500));
+        if !is_shutting_down(&cli) {
+            cli.reconnect_with_callbacks(on_connect_success, on_connect_failure);
+        }
+    });
+}
 
-        // Create the client. Use an ID for a persistent session.
-        // A real system should try harder to use a unique ID.
-        let create_opts = mqtt::CreateOptionsBuilder::new()
-            .server_uri(mqtt_connect_options.broker)
-            .client_id(mqtt_connect_options.client_id)
-            .finalize();
+/// Run the MQTT client, connecting as described by `mqtt_connect_options`.
+///
+/// Routed messages are sent through `tx` until `shutdown` is set. The client
+/// then disconnects cleanly and drops `tx`, which tells the receiving end that
+/// no more messages will arrive. The client sets `shutdown` itself if the
+/// broker kept rejecting a subscription, and returns an error then.
+///
+/// Messages that can't be routed are sent to `dead_letter` instead of being
+/// dropped. If `wal` is given, every routed message is persisted to it before
+/// the message callback returns, i.e. before the message is acknowledged.
+/// If that fails, the process exits without acknowledging the message.
+///
+/// `tx` is bounded. When it is full, the message callback waits for room,
+/// which holds back the acknowledgement and any further messages, so the
+/// broker keeps them rather than the bridge dropping them. The client
+/// library's own message stream is not used for that reason: it drops
+/// messages when its buffer is full.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_mqtt_client(
+    tx: Sender<adls::WriteJob>,
+    mqtt_connect_options: MqttConnectOptions,
+    router: Router,
+    subscriptions: Subscriptions,
+    records: RecordFormat,
+    dead_letter: DeadLetterTarget,
+    wal: Option<WriteAheadLog>,
+    shutdown: watch::Sender<bool>,
+) -> azure_core::error::Result<()> {
+    let cli = start_client(
+        tx,
+        mqtt_connect_options,
+        router,
+        subscriptions,
+        records,
+        dead_letter,
+        wal,
+        &shutdown,
+    );
 
-        // Create the client connection
-        let cli = mqtt::AsyncClient::new(create_opts).unwrap_or_else(|e| {
-            log::error!("Error creating the client: {:?}", e);
-            process::exit(1);
-        });
+    // Wait for incoming messages until we are asked to shut down, probing
+    // the connection meanwhile.
+    let mut stopped = shutdown.subscribe();
+    let mut probes = interval(PROBE_INTERVAL);
+    let probing = Arc::new(AtomicBool::new(false));
+    loop {
+        tokio::select! {
+            _ = stopped.wait_for(|stop| *stop) => break,
+            _ = probes.tick() => probe(&cli, &probing),
+        }
+    }
 
-        // Set a closure to be called whenever the client loses the connection.
-        // It will attempt to reconnect, and set up function callbacks to keep
-        // retrying until the connection is re-established.
-        cli.set_connection_lost_callback(|cli: &mqtt::AsyncClient| {
-            log::warn!("Connection lost. Attempting reconnect.");
-            thread::sleep(Duration::from_millis(2500));
-            cli.reconnect_with_callbacks(on_connect_success, on_connect_failure);
-        });
+    // Disconnecting cleanly means the broker will not publish the LWT message.
+    log::info!("Disconnecting from the MQTT server...");
+    match timeout(Duration::from_secs(10), cli.disconnect(None)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log::warn!("Error disconnecting from the MQTT server: {}", e),
+        Err(_) => log::warn!("Timed out disconnecting from the MQTT server"),
+    }
+    METRICS.mqtt_connected.set(0);
+
+    // Dropping the message callback drops `tx`, which ends the write loop.
+    // A callback still waiting for room in the queue holds on to it, so this
+    // is done off the runtime until the write loop has made room.
+    let client = cli.clone();
+    if let Err(e) = tokio::task::spawn_blocking(move || client.remove_message_callback()).await {
+        log::error!("Error removing the message callback: {}", e);
+    }
+    log::info!("MQTT client stopped");
+
+    if client_state(&cli).is_some_and(|state| state.subscribe_failed.load(Ordering::SeqCst)) {
+        return Err(Error::message(
+            ErrorKind::Other,
+            "The broker rejected a subscription",
+        ));
+    }
+    Ok(())
+}
+
+/// Make a round trip to the broker through the client library and beat
+/// `HEALTH.mqtt_thread` once it completes.
+///
+/// The broker's answer is delivered on the same thread as messages, so the
+/// probe stalls, and the bridge reports itself as not alive, if the message
+/// callback hangs, e.g. waiting for a write loop that no longer makes room.
+/// It stalls as well if the connection hangs without being reported as lost.
+/// While disconnected, the bridge counts as alive; it is not ready then.
+///
+/// The probe runs on a separate thread, since the client library may block,
+/// and `probing` keeps a stalled probe from being started again.
+fn probe(cli: &mqtt::AsyncClient, probing: &Arc<AtomicBool>) {
+    if probing.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let cli = cli.clone();
+    let probing = probing.clone();
+    thread::spawn(move || {
+        if !cli.is_connected()
+            || cli
+                .unsubscribe(PROBE_TOPIC)
+                .wait_for(PROBE_INTERVAL)
+                .is_ok()
+        {
+            HEALTH.mqtt_thread.beat();
+        }
+        probing.store(false, Ordering::SeqCst);
+    });
+}
 
-        // Attach a closure to the client to receive callback
-        // on incoming messages.
-        #[allow(unused)]
-        cli.set_message_callback(move |_cli, msg| {
-            if let Some(msg) = msg {
-                // Get the path for the message
-                let payload = get_payload(&msg).expect("Error getting path");
+/// Create the client and start connecting it as described by
+/// `mqtt_connect_options`. See `run_mqtt_client`.
+///
+/// The client library's options are not `Send`, so they are built here
+/// rather than within the future of `run_mqtt_client`.
+#[allow(clippy::too_many_arguments)]
+fn start_client(
+    tx: Sender<adls::WriteJob>,
+    mqtt_connect_options: MqttConnectOptions,
+    router: Router,
+    subscriptions: Subscriptions,
+    records: RecordFormat,
+    dead_letter: DeadLetterTarget,
+    wal: Option<WriteAheadLog>,
+    shutdown: &watch::Sender<bool>,
+) -> mqtt::AsyncClient {
+    let shared = subscriptions
+        .topics
+        .iter()
+        .any(|sub| sub.topic.starts_with("$share/"));
+    if shared && !mqtt_connect_options.is_v5() {
+        log::warn!("Shared subscriptions are part of MQTT 5. Set MQTT_VERSION=5 unless the broker supports them for 3.1.1");
+    }
+
+    // `BridgeConfig::validate` makes sure a persistent session comes with
+    // a write-ahead log.
+    if mqtt_connect_options.persistent_session
+        && mqtt_connect_options.client_id == DEFAULT_CLIENT_ID
+    {
+        log::warn!("MQTT_PERSISTENT_SESSION is set with the default MQTT_CLIENT_ID. Every bridge using it shares one session");
+    }
 
-                // Send MqttPayload with the path and payload to main thread.
-                tx.send(payload);
+    // Create the client. The ID identifies a persistent session, so it
+    // must be stable across restarts and unique per bridge.
+    let create_opts = mqtt::CreateOptionsBuilder::new()
+        .server_uri(mqtt_connect_options.broker.as_str())
+        .client_id(mqtt_connect_options.client_id.as_str())
+        .mqtt_version(mqtt_connect_options.mqtt_version)
+        // Without a persistent session the broker forgets messages in flight
+        // on reconnect, so the client has nothing to keep on disk either.
+        .persistence(if mqtt_connect_options.persistent_session {
+            mqtt::PersistenceType::File
+        } else {
+            mqtt::PersistenceType::None
+        })
+        .user_data(Box::new(ClientState {
+            shutdown: shutdown.clone(),
+            subscriptions,
+            subscribe_failed: AtomicBool::new(false),
+        }))
+        .finalize();
+
+    // Create the client connection
+    let cli = mqtt::AsyncClient::new(create_opts).unwrap_or_else(|e| {
+        log::error!("Error creating the client: {:?}", e);
+        process::exit(1);
+    });
+
+    // Set a closure to be called whenever the client loses the connection.
+    // It will attempt to reconnect, and set up function callbacks to keep
+    // retrying until the connection is re-established.
+    cli.set_connection_lost_callback(|cli: &mqtt::AsyncClient| {
+        METRICS.mqtt_connected.set(0);
+        HEALTH.set_connected(false);
+        if is_shutting_down(cli) {
+            return;
+        }
+        METRICS.mqtt_reconnects.inc();
+        log::warn!("Connection lost. Attempting reconnect.");
+        reconnect_later(cli);
+    });
+
+    // Attach a closure to the client to receive callback
+    // on incoming messages.
+    #[allow(unused)]
+    cli.set_message_callback(move |cli, msg| {
+        if let Some(msg) = msg {
+            METRICS
+                .messages_received
+                .with_label_values(&[router.route_name(msg.topic())])
+                .inc();
+
+            // Get the path for the message, or dead-letter it.
+            let payload = match get_payload(&router, &msg, &records, Utc::now()) {
+                Ok(payload) => Some(payload),
+                Err(letter) => {
+                    METRICS
+                        .messages_dropped
+                        .with_label_values(&[letter.reason])
+                        .inc();
+                    if dead_letter.is_own_topic(msg.topic()) {
+                        None
+                    } else {
+                        dead_letter.send(cli, letter)
+                    }
+                }
+            };
+
+            if let Some(mut payload) = payload {
+                // Persist routed messages before they are acknowledged.
+                if let Some(wal) = &wal {
+                    if let Err(e) = wal.append(&mut payload) {
+                        // The message is acknowledged once the callback
+                        // returns, and the client library can't disconnect
+                        // before that. Exiting closes the connection without
+                        // the acknowledgement, so the broker keeps the message.
+                        log::error!(
+                            "Error writing to the write-ahead log: {}. Exiting without acknowledging the message",
+                            e
+                        );
+                        process::exit(1);
+                    }
+                }
+
+                // Hand the job to the write loop, waiting while its queue is
+                // full. This runs on the client library's thread, not on the
+                // runtime.
+                if tx.blocking_send(payload).is_err() {
+                    log::error!("The write loop has stopped. Dropping message");
+                }
             }
+            HEALTH.mqtt_thread.beat();
+        }
+    });
+
+    // Set Last Will Message. This will be triggered if the client disconnects abrubtly.
+    let lwt = mqtt::Message::new(
+        mqtt_connect_options.lwt_topic.as_str(),
+        mqtt_connect_options.lwt_payload.as_str(),
+        1,
+    );
+
+    // Define Connection Options
+    let mut conn_builder = mqtt::ConnectOptionsBuilder::new();
+    let clean = !mqtt_connect_options.persistent_session;
+    if mqtt_connect_options.is_v5() {
+        // Setting the version first clears the v3 clean session flag,
+        // which the client library rejects for MQTT 5.
+        conn_builder
+            .mqtt_version(mqtt::MQTT_VERSION_5)
+            .clean_start(clean);
+        if !clean {
+            // Without an expiry interval, MQTT 5 drops the session on disconnect.
+            let mut props = mqtt::Properties::new();
+            props
+                .push_u32(
+                    mqtt::PropertyCode::SessionExpiryInterval,
+                    mqtt_connect_options.session_expiry_secs,
+                )
+                .expect("SessionExpiryInterval is a u32 property");
+            conn_builder.properties(props);
+        }
+    } else {
+        conn_builder.clean_session(clean);
+    }
+    conn_builder
+        .keep_alive_interval(Duration::from_secs(20))
+        .will_message(lwt)
+        .user_name(&mqtt_connect_options.username)
+        .password(&mqtt_connect_options.password);
+
+    // Only `ssl://` (and similar) URIs use TLS, the options are ignored otherwise.
+    if mqtt_connect_options.uses_tls() {
+        let ssl_opts = mqtt_connect_options.ssl_options().unwrap_or_else(|e| {
+            log::error!("Error loading the TLS options: {}", e);
+            process::exit(1);
         });
+        conn_builder.ssl_options(ssl_opts);
+    } else if !mqtt_connect_options.tls_ca_file.is_empty()
+        || !mqtt_connect_options.tls_client_cert.is_empty()
+    {
+        log::warn!("MQTT_TLS_* options are ignored, since MQTT_BROKER does not use ssl://");
+    }
+    let conn_opts = conn_builder.finalize();
 
-        // Set Last Will Message. This will be triggered if the client disconnects abrubtly.
-        let lwt = mqtt::Message::new(
-            mqtt_connect_options.lwt_topic,
-            mqtt_connect_options.lwt_payload,
-            1,
-        );
-
-        // Define Connection Options
-        let conn_opts = mqtt::ConnectOptionsBuilder::new()
-            .keep_alive_interval(Duration::from_secs(20))
-            .clean_session(true)
-            .will_message(lwt)
-            .user_name(&mqtt_connect_options.username)
-            .password(&mqtt_connect_options.password)
-            .finalize();
-
-        // Make the connection to the broker
-        log::info!("Connecting to the MQTT server...");
-        cli.connect_with_callbacks(conn_opts, on_connect_success, on_connect_failure);
-
-        // Wait for incoming messages.
-        loop {
-            thread::sleep(Duration::from_millis(1000));
-        }
-
-        // Hitting ^C will exit the app and cause the broker to publish the
-        // LWT message since we're not disconnecting cleanly.
-    });
+    // Make the connection to the broker
+    log::info!("Connecting to the MQTT server...");
+    let mut token = cli.connect_with_callbacks(conn_opts, on_connect_success, on_connect_failure);
+
+    // Invalid options fail right away, without calling `on_connect_failure`.
+    if let Some(Err(e)) = token.try_wait() {
+        log::error!("Error connecting to the MQTT server: {}", e);
+        process::exit(1);
+    }
 
-    return handle;
+    cli
 }
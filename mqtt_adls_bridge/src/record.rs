@@ -0,0 +1,124 @@
+use crate::routing::MessageProperties;
+use azure_core::error::{Error, ErrorKind, Result};
+use chrono::{DateTime, Utc};
+use serde_json::{Map, Value};
+
+/// Metadata of a received message.
+#[derive(Debug, Clone)]
+pub struct Received<'a> {
+    pub topic: &'a str,
+    pub qos: i32,
+    pub retained: bool,
+    pub received_at: DateTime<Utc>,
+    pub properties: &'a MessageProperties,
+}
+
+/// Names of the fields of an envelope. Fields with an empty name are left out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub topic: String,
+    pub received_at: String,
+    pub qos: String,
+    pub retained: String,
+    pub payload: String,
+}
+
+impl Default for Envelope {
+    fn default() -> Envelope {
+        Envelope {
+            topic: "topic".to_string(),
+            received_at: "received_at".to_string(),
+            qos: "qos".to_string(),
+            retained: "retained".to_string(),
+            payload: "payload".to_string(),
+        }
+    }
+}
+
+/// How a received message is turned into a line of the written files.
+#[derive(Debug, Clone, Default)]
+pub struct RecordFormat {
+    /// Wrap the payload in an envelope with the message metadata.
+    pub envelope: Option<Envelope>,
+    /// Field that MQTT 5 message properties are added to. Empty to leave them out.
+    pub properties_field: String,
+}
+
+impl RecordFormat {
+    /// Check that the envelope has a payload field and no field is used twice.
+    pub fn validate(&self) -> Result<()> {
+        let Some(envelope) = &self.envelope else {
+            return Ok(());
+        };
+        if envelope.payload.is_empty() {
+            return Err(Error::message(
+                ErrorKind::DataConversion,
+                "ENVELOPE_PAYLOAD_FIELD must not be empty",
+            ));
+        }
+        let mut names: Vec<&str> = [
+            &envelope.topic,
+            &envelope.received_at,
+            &envelope.qos,
+            &envelope.retained,
+            &envelope.payload,
+            &self.properties_field,
+        ]
+        .into_iter()
+        .map(String::as_str)
+        .filter(|name| !name.is_empty())
+        .collect();
+        names.sort_unstable();
+        if let Some(pair) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            let name = pair[0].to_string();
+            return Err(Error::with_message(ErrorKind::DataConversion, || {
+                format!("Envelope field '{name}' is used twice")
+            }));
+        }
+        Ok(())
+    }
+
+    /// Whether records are written as received, without envelope or properties.
+    pub fn is_plain(&self) -> bool {
+        self.envelope.is_none() && self.properties_field.is_empty()
+    }
+
+    /// Build the record for `payload`.
+    ///
+    /// Without an envelope, properties can only be added to JSON objects;
+    /// other payloads are written unchanged.
+    pub fn record(&self, received: &Received, payload: Value) -> Value {
+        let properties = (!self.properties_field.is_empty() && !received.properties.is_empty())
+            .then(|| serde_json::to_value(received.properties).ok())
+            .flatten();
+
+        let Some(envelope) = &self.envelope else {
+            return match (payload, properties) {
+                (Value::Object(mut object), Some(properties)) => {
+                    object.insert(self.properties_field.clone(), properties);
+                    Value::Object(object)
+                }
+                (payload, _) => payload,
+            };
+        };
+
+        let mut object = Map::new();
+        let mut insert = |name: &str, value: Value| {
+            if !name.is_empty() {
+                object.insert(name.to_string(), value);
+            }
+        };
+        insert(&envelope.topic, Value::from(received.topic));
+        insert(
+            &envelope.received_at,
+            serde_json::to_value(received.received_at).unwrap_or_default(),
+        );
+        insert(&envelope.qos, Value::from(received.qos));
+        insert(&envelope.retained, Value::from(received.retained));
+        if let Some(properties) = properties {
+            insert(&self.properties_field, properties);
+        }
+        insert(&envelope.payload, payload);
+        Value::Object(object)
+    }
+}
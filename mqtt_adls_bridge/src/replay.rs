@@ -0,0 +1,522 @@
+//! Messages read from files instead of the broker.
+//!
+//! Archived messages are newline-delimited JSON objects with the fields of
+//! the default envelope, so files written with `ENVELOPE=true` can be read
+//! back, as can dead letters with their `payload_base64`:
+//!
+//! ```json
+//! {"topic":"packml/status/filler","received_at":"2024-05-01T12:00:00Z","payload":{"ServiceName":"filler"}}
+//! ```
+//!
+//! Files written without the envelope have no topic, so every line of them
+//! is skipped.
+//!
+//! Files ending in `.mqtt` are raw captures instead: the bytes a broker sent
+//! to a client, e.g. the TCP stream of a packet capture. Their PUBLISH
+//! packets are read and everything else is skipped.
+//!
+//! Files are read as they are replayed, a line or a packet at a time, so
+//! they may be larger than the memory.
+//!
+//! They are routed exactly like live messages, see `ArchivedMessage::route`.
+
+use crate::{
+    adls::WriteJob,
+    dead_letter::DeadLetter,
+    mqtt::{get_payload, MqttVersion},
+    record::RecordFormat,
+    routing::{MessageProperties, Router},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::read::GzDecoder;
+use paho_mqtt as mqtt;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::Sender;
+
+/// The messages of a file, read as they are taken.
+pub type Messages = Box<dyn Iterator<Item = io::Result<ArchivedMessage>>>;
+
+/// A message as it was received from the broker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: i32,
+    pub retained: bool,
+    /// When the message was originally received, if known.
+    pub received_at: Option<DateTime<Utc>>,
+    pub properties: MessageProperties,
+}
+
+/// A line of an archive. Other fields, e.g. the `reason` of a dead letter,
+/// are ignored.
+#[derive(Deserialize)]
+struct Line {
+    topic: String,
+    payload: Option<Value>,
+    payload_base64: Option<String>,
+    #[serde(default)]
+    qos: i32,
+    #[serde(default)]
+    retained: bool,
+    received_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    properties: MessageProperties,
+}
+
+impl ArchivedMessage {
+    /// Parse a line of an archive.
+    pub fn from_json(line: &str) -> Result<ArchivedMessage, String> {
+        let line: Line = serde_json::from_str(line).map_err(|e| e.to_string())?;
+        let payload = match (line.payload, line.payload_base64) {
+            (_, Some(encoded)) => {
+                base64::decode(encoded).map_err(|e| format!("Invalid payload_base64: {e}"))?
+            }
+            (Some(payload), None) => payload.to_string().into_bytes(),
+            (None, None) => return Err("Neither payload nor payload_base64 is set".to_string()),
+        };
+        Ok(ArchivedMessage {
+            topic: line.topic,
+            payload,
+            qos: line.qos,
+            retained: line.retained,
+            received_at: line.received_at,
+            properties: line.properties,
+        })
+    }
+
+    /// The message as the client library would have delivered it.
+    pub fn to_message(&self) -> mqtt::Message {
+        let mut props = mqtt::Properties::new();
+        let properties = &self.properties;
+        if let Some(content_type) = &properties.content_type {
+            let _ = props.push_string(mqtt::PropertyCode::ContentType, content_type);
+        }
+        if let Some(expiry) = properties.message_expiry_interval {
+            let _ = props.push_u32(mqtt::PropertyCode::MessageExpiryInterval, expiry);
+        }
+        for (name, value) in &properties.user_properties {
+            let _ = props.push_string_pair(mqtt::PropertyCode::UserProperty, name, value);
+        }
+        mqtt::MessageBuilder::new()
+            .topic(self.topic.as_str())
+            .payload(self.payload.as_slice())
+            .qos(self.qos)
+            .retained(self.retained)
+            .properties(props)
+            .finalize()
+    }
+
+    /// Route the message like a live one, as if it was received at its
+    /// original time. Messages without one count as received now.
+    pub fn route(&self, router: &Router, records: &RecordFormat) -> Result<WriteJob, DeadLetter> {
+        let received_at = self.received_at.unwrap_or_else(Utc::now);
+        get_payload(router, &self.to_message(), records, received_at)
+    }
+}
+
+/// Read the messages from `reader`, one per line.
+///
+/// Blank lines are skipped. Lines that are not messages are logged with
+/// `source` and their line number, and skipped as well.
+pub fn read_messages<R: BufRead>(
+    reader: R,
+    source: &str,
+) -> impl Iterator<Item = io::Result<ArchivedMessage>> {
+    let source = source.to_string();
+    reader
+        .lines()
+        .enumerate()
+        .filter_map(move |(n, line)| match line {
+            Ok(line) if line.trim().is_empty() => None,
+            Ok(line) => match ArchivedMessage::from_json(&line) {
+                Ok(message) => Some(Ok(message)),
+                Err(e) => {
+                    log::warn!("Skipping line {} of {}: {}", n + 1, source, e);
+                    None
+                }
+            },
+            Err(e) => Some(Err(e)),
+        })
+}
+
+/// Read the PUBLISH packets of a raw capture, sent to a client of `version`.
+///
+/// Packets that can't be decoded are logged with `source` and skipped. A
+/// capture may end in the middle of a packet, which is dropped.
+pub fn read_capture<R: Read>(
+    reader: R,
+    version: MqttVersion,
+    source: &str,
+) -> impl Iterator<Item = io::Result<ArchivedMessage>> {
+    Packets {
+        reader: BufReader::new(reader),
+        version,
+        source: source.to_string(),
+        aliases: HashMap::new(),
+        n: 0,
+        done: false,
+    }
+}
+
+/// The PUBLISH packets of a raw capture.
+struct Packets<R> {
+    reader: BufReader<R>,
+    version: MqttVersion,
+    /// Where the capture comes from, for the log.
+    source: String,
+    /// Topics of the MQTT 5 topic aliases set so far.
+    aliases: HashMap<u16, String>,
+    /// Number of the last packet read.
+    n: usize,
+    /// Whether the capture ended or could not be read.
+    done: bool,
+}
+
+impl<R: Read> Iterator for Packets<R> {
+    type Item = io::Result<ArchivedMessage>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let (header, body) = match self.packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            // Everything but PUBLISH, e.g. CONNACK, SUBACK and PINGRESP.
+            if header >> 4 != 3 {
+                continue;
+            }
+            match self.publish(header, &body, self.version) {
+                Some(message) => return Some(Ok(message)),
+                None => log::warn!(
+                    "Skipping packet {} of {}: invalid PUBLISH",
+                    self.n,
+                    self.source
+                ),
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+impl<R: Read> Packets<R> {
+    /// Read the next packet, returning its first byte and its body. `None`
+    /// at the end of the capture.
+    fn packet(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        let mut byte = [0];
+        if self.reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        self.n += 1;
+        let header = byte[0];
+        match self.body() {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                log::warn!("Capture {} ends within packet {}", self.source, self.n);
+                Ok(None)
+            }
+            Err(e) => Err(e),
+            Ok(body) => Ok(Some((header, body))),
+        }
+    }
+
+    /// Read the remaining length and the body of a packet.
+    fn body(&mut self) -> io::Result<Vec<u8>> {
+        let mut body = vec![0; self.varint()?];
+        self.reader.read_exact(&mut body)?;
+        Ok(body)
+    }
+
+    /// A variable byte integer of at most four bytes.
+    fn varint(&mut self) -> io::Result<usize> {
+        let mut value = 0;
+        for i in 0..4 {
+            let mut byte = [0];
+            self.reader.read_exact(&mut byte)?;
+            value += usize::from(byte[0] & 127) << (7 * i);
+            if byte[0] & 128 == 0 {
+                return Ok(value);
+            }
+        }
+        // Not the start of a packet, so the rest can't be read either.
+        Err(io::ErrorKind::UnexpectedEof.into())
+    }
+
+    /// Decode the body of a PUBLISH packet.
+    fn publish(
+        &mut self,
+        header: u8,
+        body: &[u8],
+        version: MqttVersion,
+    ) -> Option<ArchivedMessage> {
+        let mut cursor = Cursor(body);
+        let qos = i32::from((header >> 1) & 3);
+        let mut topic = cursor.string()?;
+        if qos > 0 {
+            cursor.u16()?;
+        }
+        let mut properties = MessageProperties::default();
+        if version == MqttVersion::V5 {
+            let length = cursor.varint()?;
+            let mut props = Cursor(cursor.take(length)?);
+            while !props.0.is_empty() {
+                // Properties the bridge doesn't use are skipped.
+                match props.varint()? {
+                    0x01 => {
+                        props.u8()?;
+                    }
+                    0x02 => properties.message_expiry_interval = Some(props.u32()?),
+                    0x03 => properties.content_type = Some(props.string()?),
+                    0x08 => {
+                        props.string()?;
+                    }
+                    0x09 => {
+                        let length = props.u16()?;
+                        props.take(usize::from(length))?;
+                    }
+                    0x0B => {
+                        props.varint()?;
+                    }
+                    0x23 => {
+                        // An alias with a topic sets it, one without uses it.
+                        let alias = props.u16()?;
+                        if topic.is_empty() {
+                            topic = self.aliases.get(&alias)?.clone();
+                        } else {
+                            self.aliases.insert(alias, topic.clone());
+                        }
+                    }
+                    0x26 => {
+                        let name = props.string()?;
+                        properties.user_properties.insert(name, props.string()?);
+                    }
+                    _ => return None,
+                }
+            }
+        }
+        if topic.is_empty() {
+            return None;
+        }
+        Some(ArchivedMessage {
+            topic,
+            payload: cursor.0.to_vec(),
+            qos,
+            retained: header & 1 == 1,
+            received_at: None,
+            properties,
+        })
+    }
+}
+
+/// Reads the fields of a packet, `None` if it is too short.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    /// A variable byte integer of at most four bytes.
+    fn varint(&mut self) -> Option<usize> {
+        let mut value = 0;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            value += usize::from(byte & 127) << (7 * i);
+            if byte & 128 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let length = usize::from(self.u16()?);
+        String::from_utf8(self.take(length)?.to_vec()).ok()
+    }
+}
+
+/// Read the messages in `file`, decompressing it if it ends in `.gz` or `.zst`.
+///
+/// Files ending in `.mqtt` are read as raw captures of a client of
+/// `version`, everything else as one message per line.
+pub fn read_file(file: &Path, version: MqttVersion) -> io::Result<Messages> {
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    let (name, reader): (&str, Box<dyn Read>) = if let Some(name) = name.strip_suffix(".gz") {
+        (name, Box::new(GzDecoder::new(File::open(file)?)))
+    } else if let Some(name) = name.strip_suffix(".zst") {
+        (name, Box::new(zstd::Decoder::new(File::open(file)?)?))
+    } else {
+        (&name, Box::new(File::open(file)?))
+    };
+    let source = file.display().to_string();
+    Ok(if name.ends_with(".mqtt") {
+        Box::new(read_capture(reader, version, &source))
+    } else {
+        Box::new(read_messages(BufReader::new(reader), &source))
+    })
+}
+
+/// The files below `dir` that can hold archived messages, sorted by path.
+/// If `dir` is a file, it is the only one.
+///
+/// The bridge names files by time, so within a directory they are read in
+/// the order they were written. Parquet files are skipped.
+pub fn archive_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    if dir.is_file() {
+        return Ok(vec![dir.to_path_buf()]);
+    }
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            if name.starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                dirs.push(path);
+            } else if name.ends_with(".parquet") {
+                log::warn!(
+                    "Skipping {}, Parquet files can't be replayed",
+                    path.display()
+                );
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Parse the bound of a time range, either RFC 3339 or a date, which is
+/// taken as midnight UTC.
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        .map_err(|_| {
+            format!("Invalid time '{s}'. Use RFC 3339, e.g. 2024-05-01T12:00:00Z, or a date")
+        })
+}
+
+/// Which messages are replayed, and how fast.
+#[derive(Debug, Clone, Default)]
+pub struct ReplayOptions {
+    /// Messages per second at most. `None` replays as fast as they are read.
+    pub rate: Option<f64>,
+    /// Only messages routed at this time or later.
+    pub from: Option<DateTime<Utc>>,
+    /// Only messages routed before this time.
+    pub until: Option<DateTime<Utc>>,
+    /// How raw captures are decoded.
+    pub version: MqttVersion,
+}
+
+impl ReplayOptions {
+    /// Whether a message routed at `time` is in the time range.
+    pub fn includes(&self, time: DateTime<Utc>) -> bool {
+        self.from.is_none_or(|from| from <= time) && self.until.is_none_or(|until| time < until)
+    }
+}
+
+/// What a replay did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub files: usize,
+    /// Messages handed to the write loop.
+    pub routed: usize,
+    /// Messages outside the time range.
+    pub filtered: usize,
+    /// Messages that could not be routed.
+    pub unroutable: usize,
+}
+
+/// Route the messages in `files` and hand them to the write loop through `tx`.
+///
+/// Messages are filtered by the time they are routed at, i.e. their event
+/// time or else the time they were originally received. Those that can't be
+/// routed are logged and skipped, as they were dead-lettered when they were
+/// first received.
+///
+/// This blocks, also while `tx` is full, so it must not run on the async
+/// runtime itself. Use `tokio::task::spawn_blocking` or a thread of its own.
+pub fn replay_files(
+    files: &[PathBuf],
+    router: &Router,
+    records: &RecordFormat,
+    options: &ReplayOptions,
+    tx: &Sender<WriteJob>,
+) -> io::Result<ReplayStats> {
+    let mut stats = ReplayStats::default();
+    let started = Instant::now();
+    for file in files {
+        stats.files += 1;
+        for message in read_file(file, options.version)? {
+            let message = message?;
+            let job = match message.route(router, records) {
+                Ok(job) => job,
+                Err(letter) => {
+                    stats.unroutable += 1;
+                    log::warn!(
+                        "Skipping {} message on '{}' from {}",
+                        letter.reason,
+                        message.topic,
+                        file.display()
+                    );
+                    continue;
+                }
+            };
+            if !job.time.is_some_and(|time| options.includes(time)) {
+                stats.filtered += 1;
+                continue;
+            }
+
+            // Wait until the message is due, so the rate is kept on average.
+            if let Some(rate) = options.rate {
+                let due = Duration::from_secs_f64(stats.routed as f64 / rate);
+                if let Some(wait) = due.checked_sub(started.elapsed()) {
+                    thread::sleep(wait);
+                }
+            }
+            stats.routed += 1;
+            if tx.blocking_send(job).is_err() {
+                return Err(io::Error::other("The write loop stopped"));
+            }
+        }
+    }
+    Ok(stats)
+}
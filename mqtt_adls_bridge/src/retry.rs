@@ -0,0 +1,71 @@
+use azure_core::error::{ErrorKind, Result};
+use rand::Rng;
+use std::{future::Future, time::Duration};
+
+/// How failed uploads are retried.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry. Doubles with every attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound for the delay between two attempts.
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay after `attempt` (starting at 1) has failed.
+    ///
+    /// Half of the exponential backoff is fixed and the other half is random,
+    /// so that several bridges don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_backoff);
+        let half = exp / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    /// Run `op` until it succeeds or `max_attempts` is reached.
+    ///
+    /// Errors converting data are not retried, since they would fail again.
+    /// Returns the error of the last attempt.
+    pub async fn run<F, Fut, T>(&self, what: &str, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Ok(t) => return Ok(t),
+                Err(e) if attempt >= self.max_attempts => return Err(e),
+                Err(e) if matches!(e.kind(), ErrorKind::DataConversion) => return Err(e),
+                Err(e) => {
+                    let delay = self.backoff(attempt);
+                    log::warn!(
+                        "Attempt {}/{} of {} failed: {}. Retrying in {:?}",
+                        attempt,
+                        self.max_attempts,
+                        what,
+                        e,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use crate::{adls, event_time::EventTime, format::FileFormat, metrics::METRICS, utils};
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, fs, io};
+
+/// Rules shipped with the bridge. Used when `MQTT_ROUTES_FILE` is not set.
+const DEFAULT_ROUTES: &str = include_str!("../routes.yaml");
+
+/// MQTT v5 properties of a message.
+///
+/// Routes can use them as fields starting with `$`, e.g.
+/// `$user_properties/site` or `$content_type`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessageProperties {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Lifetime of the message in seconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_expiry_interval: Option<u32>,
+    /// User properties. If a name is repeated, the last value wins.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub user_properties: BTreeMap<String, String>,
+}
+
+impl MessageProperties {
+    pub fn is_empty(&self) -> bool {
+        self == &MessageProperties::default()
+    }
+}
+
+/// A single routing rule.
+///
+/// Messages on a topic matching `topic` are written to the path obtained by
+/// rendering `path` with the values extracted through `fields`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Route {
+    /// Name used in logs.
+    #[serde(default)]
+    pub name: String,
+    /// MQTT topic filter, e.g. `packml/+/event/#`.
+    pub topic: String,
+    /// Placeholder name to JSON pointer, e.g. `machine_idx: /machineIDx`, or
+    /// to a message property, e.g. `site: $user_properties/site`.
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Path template, e.g. `packml/event/machine_idx={machine_idx}/year={yyyy}`.
+    pub path: String,
+    /// Number of messages to buffer before a file is written.
+    #[serde(default = "default_n_per_file")]
+    pub n_per_file: i32,
+    /// Format of the written files, e.g. `format: parquet`.
+    #[serde(flatten)]
+    pub file_format: FileFormat,
+    /// Where the time of the date placeholders comes from. By default it is
+    /// the time the message was received, in UTC.
+    #[serde(default)]
+    pub time: EventTime,
+    /// Container, prefix and file extension, e.g. `container: curated`.
+    /// Unset ones use `ADLS_CONTAINER`, `ADLS_PREFIX` and `ADLS_FILE_EXTENSION`.
+    #[serde(flatten)]
+    pub destination: adls::Destination,
+}
+
+fn default_n_per_file() -> i32 {
+    1
+}
+
+/// An ordered list of routes. The first route that matches a message wins.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Router {
+    pub routes: Vec<Route>,
+}
+
+impl Router {
+    /// Parse and validate routes from a YAML document.
+    pub fn from_yaml(s: &str) -> io::Result<Router> {
+        let router: Router = serde_yaml::from_str(s).map_err(invalid)?;
+        for route in router.routes.iter() {
+            if !is_valid_filter(&route.topic) {
+                return Err(invalid(format!(
+                    "Invalid topic filter '{}' in route '{}'",
+                    route.topic, route.name
+                )));
+            }
+            if route.n_per_file < 1 {
+                return Err(invalid(format!(
+                    "'n_per_file' must be at least 1 in route '{}'",
+                    route.name
+                )));
+            }
+            if let Some((name, _)) = route
+                .fields
+                .iter()
+                .find(|(_, pointer)| !pointer.starts_with('/') && !pointer.starts_with('$'))
+            {
+                return Err(invalid(format!(
+                    "Field '{}' in route '{}' must start with '/' or '$'",
+                    name, route.name
+                )));
+            }
+            route
+                .file_format
+                .validate()
+                .map_err(|e| invalid(format!("Invalid format in route '{}': {}", route.name, e)))?;
+            route
+                .time
+                .validate()
+                .map_err(|e| invalid(format!("{} in route '{}'", e, route.name)))?;
+            route
+                .destination
+                .validate()
+                .map_err(|e| invalid(format!("{} in route '{}'", e, route.name)))?;
+            // Render with dummy values to catch unknown or unclosed placeholders.
+            render(&route.path, |key| {
+                route
+                    .fields
+                    .get(key)
+                    .cloned()
+                    .or_else(|| time_token(key, &Utc::now()))
+            })
+            .ok_or_else(|| {
+                invalid(format!(
+                    "Invalid path template '{}' in route '{}'",
+                    route.path, route.name
+                ))
+            })?;
+        }
+        Ok(router)
+    }
+
+    /// Load routes from `file`, or the built-in routes if it is empty.
+    pub fn load(file: &str) -> io::Result<Router> {
+        if file.is_empty() {
+            return Router::from_yaml(DEFAULT_ROUTES);
+        }
+        log::info!("Loading routes from '{file}'");
+        Router::from_yaml(&fs::read_to_string(file)?)
+    }
+
+    /// Check that routes which may render the same location agree on how it
+    /// is written.
+    ///
+    /// Messages for one location are batched together, and a batch is
+    /// written with the `n_per_file`, format and extension of its first
+    /// message. Routes whose paths can't render the same location, e.g.
+    /// because a literal part differs, may use different settings.
+    pub fn check_locations(&self, defaults: &adls::Destination) -> io::Result<()> {
+        let locations: Vec<(&Route, adls::Destination, Vec<Token>)> = self
+            .routes
+            .iter()
+            .map(|route| {
+                let destination = route.destination.or(defaults);
+                let tokens = route.location_tokens(&destination);
+                (route, destination, tokens)
+            })
+            .collect();
+
+        for (i, (a, a_destination, a_tokens)) in locations.iter().enumerate() {
+            for (b, b_destination, b_tokens) in &locations[i + 1..] {
+                let extension = |route: &Route, destination: &adls::Destination| {
+                    destination
+                        .extension
+                        .clone()
+                        .unwrap_or_else(|| route.file_format.extension().to_string())
+                };
+                let same_settings = a.n_per_file == b.n_per_file
+                    && a.file_format == b.file_format
+                    && extension(a, a_destination) == extension(b, b_destination);
+                if !same_settings && may_overlap(a_tokens, b_tokens) {
+                    return Err(invalid(format!(
+                        "Routes '{}' and '{}' may write to the same location '{}', but differ in 'n_per_file', format or extension",
+                        a.name,
+                        b.name,
+                        a_destination.location(&a.path)
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The containers that routes write to explicitly.
+    pub fn containers(&self) -> Vec<String> {
+        let mut containers: Vec<String> = self
+            .routes
+            .iter()
+            .filter_map(|route| route.destination.container.clone())
+            .collect();
+        containers.sort();
+        containers.dedup();
+        containers
+    }
+
+    /// Find the first route matching `topic` for which every field is present
+    /// in `payload` or `properties`, and return it with the rendered path and
+    /// the event time of the message.
+    ///
+    /// `now` is used if the route has no time field or the payload has no
+    /// valid time.
+    pub fn route(
+        &self,
+        topic: &str,
+        payload: &Value,
+        properties: &MessageProperties,
+        now: DateTime<Utc>,
+    ) -> Option<(&Route, String, DateTime<Utc>)> {
+        let properties = serde_json::to_value(properties).unwrap_or_default();
+        self.routes
+            .iter()
+            .filter(|route| topic_matches(&route.topic, topic))
+            .find_map(|route| {
+                let time = route.event_time(payload, &properties).unwrap_or(now);
+                let path = route.render(payload, &properties, time)?;
+                log::debug!("Route '{}' matched {topic} -> {path}", route.name);
+                Some((route, path, time))
+            })
+    }
+
+    /// The name of the first route whose topic filter matches `topic`, or
+    /// `unroutable`. Labels metrics of messages before they are routed,
+    /// without one series for every topic.
+    pub fn route_name(&self, topic: &str) -> &str {
+        self.routes
+            .iter()
+            .find(|route| topic_matches(&route.topic, topic))
+            .map_or("unroutable", |route| route.name.as_str())
+    }
+
+    /// Construct a `WriteJob` for a message received on `topic`.
+    ///
+    /// Messages that match no route get an empty path.
+    pub fn write_job(
+        &self,
+        topic: &str,
+        payload: &Value,
+        properties: &MessageProperties,
+        now: DateTime<Utc>,
+    ) -> adls::WriteJob {
+        match self.route(topic, payload, properties, now) {
+            Some((route, path, time)) => {
+                METRICS
+                    .messages_routed
+                    .with_label_values(&[&route.name])
+                    .inc();
+                adls::WriteJob {
+                    path,
+                    payload: payload.to_string(),
+                    n_per_file: route.n_per_file,
+                    format: route.file_format.clone(),
+                    time: Some(time),
+                    destination: route.destination.clone(),
+                    ..Default::default()
+                }
+            }
+            None => adls::WriteJob {
+                payload: payload.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl Route {
+    /// Render the path template. Returns `None` if a field is missing or
+    /// its value is not a single path segment.
+    ///
+    /// Date placeholders are filled from `time` in the time zone of the route.
+    fn render(&self, payload: &Value, properties: &Value, time: DateTime<Utc>) -> Option<String> {
+        let mut values = BTreeMap::new();
+        for (name, pointer) in self.fields.iter() {
+            let value = match lookup(pointer, payload, properties) {
+                None | Some(Value::Null) => return None,
+                Some(v) => utils::value_to_string(v),
+            };
+            // The values come from the message, so they must not be able to
+            // escape the directory of the route.
+            if !is_segment(&value) {
+                log::warn!(
+                    "Field '{}' in route '{}' is not a valid path segment: {:?}",
+                    name,
+                    self.name,
+                    value
+                );
+                return None;
+            }
+            values.insert(name.as_str(), value);
+        }
+
+        let time = time.with_timezone(&self.time.timezone);
+        render(&self.path, |key| {
+            values.get(key).cloned().or_else(|| time_token(key, &time))
+        })
+    }
+
+    /// The location template of the route in `destination`, as tokens that
+    /// describe what each placeholder may render to.
+    fn location_tokens(&self, destination: &adls::Destination) -> Vec<Token> {
+        let location = destination.location(&self.path);
+        let mut tokens = Vec::new();
+        let mut rest = location.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = start + rest[start..].find('}').unwrap_or(rest.len() - start);
+            tokens.extend(rest[..start].chars().map(Token::Char));
+            let key = &rest[start + 1..end.min(rest.len())];
+            let digits = match key {
+                _ if self.fields.contains_key(key) => None,
+                "yyyy" => Some([Token::Digit; 4].as_slice()),
+                "mm" | "dd" | "hh" => Some([Token::Digit; 2].as_slice()),
+                "m" | "d" | "h" => Some([Token::Digit, Token::OptionalDigit].as_slice()),
+                _ => None,
+            };
+            tokens.extend_from_slice(digits.unwrap_or(&[Token::Segment]));
+            rest = &rest[(end + 1).min(rest.len())..];
+        }
+        tokens.extend(rest.chars().map(Token::Char));
+        tokens
+    }
+
+    /// Read the event time from the time field of the route, if it has one.
+    fn event_time(&self, payload: &Value, properties: &Value) -> Option<DateTime<Utc>> {
+        let field = self.time.field.as_deref()?;
+        let value = lookup(field, payload, properties)?;
+        let time = self.time.parse(value);
+        if time.is_none() {
+            log::warn!(
+                "Invalid time {} at '{}' in route '{}', using the receive time",
+                value,
+                field,
+                self.name
+            );
+        }
+        time
+    }
+}
+
+/// Look up a field starting with `$` in `properties`, and others in `payload`.
+fn lookup<'a>(pointer: &str, payload: &'a Value, properties: &'a Value) -> Option<&'a Value> {
+    match pointer.strip_prefix('$') {
+        Some(property) => properties.pointer(&format!("/{property}")),
+        None => payload.pointer(pointer),
+    }
+}
+
+/// Replace every `{key}` in `template` with `lookup(key)`.
+///
+/// Returns `None` if a placeholder is unclosed or `lookup` has no value for it.
+fn render<F>(template: &str, lookup: F) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        out.push_str(&rest[..start]);
+        out.push_str(&lookup(&rest[start + 1..end])?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+/// What a part of a location template renders to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    /// A literal character.
+    Char(char),
+    /// A digit of a date placeholder.
+    Digit,
+    /// A digit that may be left out, e.g. the second one of `{m}`.
+    OptionalDigit,
+    /// A field value: any number of characters within one path segment.
+    Segment,
+}
+
+impl Token {
+    /// Whether the token can be skipped without rendering a character.
+    fn is_optional(&self) -> bool {
+        matches!(self, Token::OptionalDigit | Token::Segment)
+    }
+
+    /// Whether both tokens can render the same character.
+    fn intersects(&self, other: &Token) -> bool {
+        match (self, other) {
+            (Token::Char(a), Token::Char(b)) => a == b,
+            (Token::Char(c), Token::Digit | Token::OptionalDigit)
+            | (Token::Digit | Token::OptionalDigit, Token::Char(c)) => c.is_ascii_digit(),
+            (Token::Char(c), Token::Segment) | (Token::Segment, Token::Char(c)) => *c != '/',
+            _ => true,
+        }
+    }
+}
+
+/// Whether some location can be rendered by both templates.
+///
+/// Walks both templates at once, e.g. `a/{id}` and `a/b{yyyy}`, where
+/// every step either skips an optional token or renders a character that
+/// both tokens allow.
+fn may_overlap(a: &[Token], b: &[Token]) -> bool {
+    let mut seen = vec![vec![false; b.len() + 1]; a.len() + 1];
+    let mut pending = vec![(0, 0)];
+    while let Some((i, j)) = pending.pop() {
+        if std::mem::replace(&mut seen[i][j], true) {
+            continue;
+        }
+        if i == a.len() && j == b.len() {
+            return true;
+        }
+        let (ta, tb) = (a.get(i), b.get(j));
+        if ta.is_some_and(Token::is_optional) {
+            pending.push((i + 1, j));
+        }
+        if tb.is_some_and(Token::is_optional) {
+            pending.push((i, j + 1));
+        }
+        if let (Some(ta), Some(tb)) = (ta, tb) {
+            if ta.intersects(tb) {
+                // A field value may render more characters.
+                let next_i = if *ta == Token::Segment { i } else { i + 1 };
+                let next_j = if *tb == Token::Segment { j } else { j + 1 };
+                pending.push((next_i, next_j));
+            }
+        }
+    }
+    false
+}
+
+/// Whether `value` can be used as a single level of a path, i.e. has no
+/// separator and is not `.` or `..`.
+fn is_segment(value: &str) -> bool {
+    !matches!(value, "." | "..") && !value.contains(['/', '\\', '\0'])
+}
+
+/// Expand a date placeholder. `{mm}`, `{dd}` and `{hh}` are zero-padded,
+/// `{m}`, `{d}` and `{h}` are not.
+fn time_token<T: TimeZone>(key: &str, now: &DateTime<T>) -> Option<String> {
+    let s = match key {
+        "yyyy" => now.year().to_string(),
+        "mm" => format!("{:02}", now.month()),
+        "m" => now.month().to_string(),
+        "dd" => format!("{:02}", now.day()),
+        "d" => now.day().to_string(),
+        "hh" => format!("{:02}", now.hour()),
+        "h" => now.hour().to_string(),
+        _ => return None,
+    };
+    Some(s)
+}
+
+fn invalid<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Check that `#` only appears as the last level and wildcards fill a level.
+pub(crate) fn is_valid_filter(filter: &str) -> bool {
+    let levels: Vec<&str> = filter.split('/').collect();
+    levels.iter().enumerate().all(|(i, level)| match *level {
+        "#" => i == levels.len() - 1,
+        "+" => true,
+        _ => !level.contains('#') && !level.contains('+'),
+    })
+}
+
+/// Match an MQTT topic against a topic filter with `+` and `#` wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut topic_levels = topic.split('/');
+    for level in filter.split('/') {
+        match (level, topic_levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => continue,
+            (l, Some(t)) if l == t => continue,
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
@@ -0,0 +1,72 @@
+use crate::{
+    health::{Status, HEALTH},
+    metrics::METRICS,
+};
+use azure_core::error::{Error, ErrorKind, Result};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use std::{convert::Infallible, net::SocketAddr, time::Duration};
+use tokio::task::JoinHandle;
+
+/// Serve `/metrics`, `/healthz` and `/readyz` on `addr`, e.g.
+/// `0.0.0.0:9090`.
+///
+/// `/healthz` fails if the MQTT thread or the write loop has not made progress
+/// for `max_stall`. Returns `None` if `addr` is `None`.
+pub fn start_server(
+    addr: Option<SocketAddr>,
+    max_stall: Duration,
+) -> Result<Option<JoinHandle<()>>> {
+    let Some(addr) = addr else {
+        return Ok(None);
+    };
+    let server = Server::try_bind(&addr)
+        .map_err(|e| Error::full(ErrorKind::Io, e, format!("Error binding to {addr}")))?;
+
+    let make_service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request| {
+            handle_request(request, max_stall)
+        }))
+    });
+    log::info!("Serving metrics and health probes on http://{addr}");
+    Ok(Some(tokio::spawn(async move {
+        if let Err(e) = server.serve(make_service).await {
+            log::error!("HTTP server stopped: {}", e);
+        }
+    })))
+}
+
+async fn handle_request(
+    request: Request<Body>,
+    max_stall: Duration,
+) -> std::result::Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, METRICS.content_type())
+            .body(Body::from(METRICS.render())),
+        (&Method::GET, "/healthz") => status_response(HEALTH.liveness(max_stall)),
+        (&Method::GET, "/readyz") => status_response(HEALTH.readiness()),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.expect("The response is valid"))
+}
+
+/// Respond with `status` as JSON, and 503 if a check failed.
+fn status_response(status: Status) -> hyper::http::Result<Response<Body>> {
+    let code = if status.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            serde_json::to_string(&status).expect("A status can be serialized"),
+        ))
+}
@@ -0,0 +1,148 @@
+use crate::{adls, config::BridgeConfig};
+use async_trait::async_trait;
+use azure_core::error::{Error, ErrorKind, Result};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+/// How the content of a file is encoded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub content_type: &'static str,
+    /// The compression applied to the whole file, e.g. `gzip`.
+    pub content_encoding: Option<&'static str>,
+}
+
+/// A destination for the files written by the bridge.
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Write `content` to `file_path` in `container`, replacing any existing
+    /// file.
+    async fn write(
+        &self,
+        container: &str,
+        file_path: &str,
+        content: Bytes,
+        metadata: &FileMetadata,
+    ) -> Result<()>;
+
+    /// Check that `container` exists, creating it if `create` is set.
+    ///
+    /// Sinks without containers accept every name.
+    async fn check_container(&self, _container: &str, _create: bool) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes files below a directory on the local filesystem.
+///
+/// Uses the same `{container}/{prefix}/{path}/{ts}-{uuid}.json` layout as
+/// ADLS, with a directory per container, so the directory can later be synced
+/// to the datalake as-is. Metadata is not stored; the
+/// file extension tells the codec.
+#[derive(Debug, Clone)]
+pub struct LocalSink {
+    pub root: PathBuf,
+}
+
+impl LocalSink {
+    pub fn new<P: Into<PathBuf>>(root: P) -> LocalSink {
+        LocalSink { root: root.into() }
+    }
+}
+
+#[async_trait]
+impl Sink for LocalSink {
+    async fn write(
+        &self,
+        container: &str,
+        file_path: &str,
+        content: Bytes,
+        _: &FileMetadata,
+    ) -> Result<()> {
+        // Routes only render single path segments, but the sink is also used
+        // for dead letters and must never write outside of `root`.
+        let relative = Path::new(container).join(file_path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(Error::with_message(ErrorKind::Io, || {
+                format!(
+                    "Refusing to write outside of the sink: '{}'",
+                    relative.display()
+                )
+            }));
+        }
+        let target = self.root.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // Write to a temporary file first so readers never see a partial file.
+        // Both the file and the rename are synced, since the write-ahead log
+        // entries of a dead-lettered batch are removed once this returns.
+        let tmp = target.with_extension("tmp");
+        log::debug!("Writing {} bytes to '{}'", content.len(), target.display());
+        let mut file = fs::File::create(&tmp).await?;
+        file.write_all(&content).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, &target).await?;
+        if let Some(parent) = target.parent() {
+            fs::File::open(parent).await?.sync_all().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check every container in `containers`, creating missing ones if
+/// `create` is set.
+pub async fn check_containers<'a, I>(sink: &dyn Sink, containers: I, create: bool) -> Result<()>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    for container in containers {
+        sink.check_container(container, create).await?;
+    }
+    Ok(())
+}
+
+/// The sinks that can be selected with `SINK`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SinkKind {
+    /// Azure Datalake Gen2.
+    #[default]
+    Adls,
+    /// A directory on the local filesystem.
+    Local,
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<SinkKind, String> {
+        crate::config::parse_name(s)
+    }
+}
+
+/// Create the sink selected in `config`.
+///
+/// `adls` (the default) writes to Azure Datalake Gen2 and `local` writes
+/// below `LOCAL_SINK_DIR`.
+pub async fn create_sink(config: &BridgeConfig) -> Result<Box<dyn Sink>> {
+    match config.sink {
+        SinkKind::Adls => {
+            let data_lake_client =
+                adls::create_data_lake_client(&config.adls.account_name, config.adls_auth()?)
+                    .await?;
+            Ok(Box::new(adls::AdlsSink::new(data_lake_client)))
+        }
+        SinkKind::Local => Ok(Box::new(LocalSink::new(&config.local_sink_dir))),
+    }
+}
@@ -1,43 +1,10 @@
-use dotenv::dotenv;
 use log;
 use serde_json::Value;
-use std::env;
+use tokio::signal;
 
-pub fn env_default(key: &str, default: &str) -> String {
-    // Set partial keys where values should be masked in logs.
-    let mask_array = ["password".to_string(), "cert".to_string()];
-
-    // Mask any values where keys partially match those in `mask_array`.
-    let mask = mask_array.iter().any(|e| key.to_lowercase().contains(e));
-
-    // Get the value from the environment.
-    match env::var(key) {
-        Ok(s) => {
-            if mask {
-                log::debug!("'{key}' set to '********'")
-            } else {
-                log::debug!("'{key}' set to '{s}'")
-            }
-        }
-        Err(_) => {
-            log::info!("'{key}' not set. Defaults to '{default}'");
-            env::set_var(key, default);
-        }
-    }
-
-    return env::var(key).expect("Something went wrong while setting env vars...");
-}
-
-/// Initialize logging
-///
-/// Here we pull also initialize defaults
-pub fn init_log() {
-    // Possibly load .env file
-    dotenv().ok();
-    // Get the log level from the environment. Default to INFO.
-    env_default("RUST_LOG", "info");
-    // Initiate logger.
-    env_logger::init();
+/// Initialize logging with `level`, e.g. `info` or `mqtt_adls_bridge=debug`.
+pub fn init_log(level: &str) {
+    env_logger::Builder::new().parse_filters(level).init();
 }
 
 /// Turn a serde_json::Value into a string.
@@ -45,5 +12,30 @@ pub fn init_log() {
 /// Also removes "\"" since these are parsed literally by serde_json.
 pub fn value_to_string(v: &Value) -> String {
     // Values evaluate "" literally when parsing jons, hence we replace here.
-    return v.to_string().trim().replace("\"", "");
+    v.to_string().trim().replace('"', "")
+}
+
+/// Wait until the process receives SIGINT (Ctrl-C) or SIGTERM.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => log::info!("Received SIGINT"),
+        _ = terminate => log::info!("Received SIGTERM"),
+    }
 }
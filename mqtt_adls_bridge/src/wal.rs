@@ -0,0 +1,101 @@
+use crate::adls::WriteJob;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+/// A write-ahead log of `WriteJob`s that have not yet been uploaded.
+///
+/// Every job is stored in its own file, named so that lexical order is the
+/// order in which the jobs were received. A file is removed once the batch
+/// containing the job has been uploaded, and any remaining files are replayed
+/// on startup.
+#[derive(Debug, Clone)]
+pub struct WriteAheadLog {
+    pub dir: PathBuf,
+}
+
+impl WriteAheadLog {
+    /// Open (and create) the log in `dir`.
+    ///
+    /// Temporary files left by a crash during `append` are removed. Their
+    /// jobs were never acknowledged, so the broker delivers them again.
+    pub fn open<P: Into<PathBuf>>(dir: P) -> io::Result<WriteAheadLog> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|extension| extension == "tmp") {
+                log::warn!(
+                    "Removing incomplete write-ahead log entry {}",
+                    path.display()
+                );
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(WriteAheadLog { dir })
+    }
+
+    /// Persist `job` and set its `wal_id`. Returns once the job is on disk.
+    pub fn append(&self, job: &mut WriteJob) -> io::Result<()> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let id = format!("{nanos:020}-{}", Uuid::new_v4());
+
+        // Write to a temporary file first, so a crash never leaves a partial entry.
+        let tmp = self.dir.join(format!("{id}.tmp"));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(job)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.entry(&id))?;
+        // The rename is only durable once the directory is synced as well.
+        File::open(&self.dir)?.sync_all()?;
+
+        job.wal_id = Some(id);
+        Ok(())
+    }
+
+    /// Remove the entries of jobs that have been uploaded.
+    pub fn remove(&self, ids: &[String]) -> io::Result<()> {
+        for id in ids {
+            match fs::remove_file(self.entry(id)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Read every job that has not been uploaded, oldest first.
+    pub fn replay(&self) -> io::Result<Vec<WriteJob>> {
+        let mut ids: Vec<String> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                name.strip_suffix(".json").map(|id| id.to_string())
+            })
+            .collect();
+        ids.sort();
+
+        let mut jobs = Vec::with_capacity(ids.len());
+        for id in ids {
+            match serde_json::from_slice::<WriteJob>(&fs::read(self.entry(&id))?) {
+                Ok(mut job) => {
+                    job.wal_id = Some(id);
+                    jobs.push(job);
+                }
+                Err(e) => log::warn!("Skipping unreadable write-ahead log entry {id}: {e}"),
+            }
+        }
+        Ok(jobs)
+    }
+
+    fn entry(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.json"))
+    }
+}
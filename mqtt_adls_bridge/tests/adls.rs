@@ -0,0 +1,536 @@
+//! Tests of the ADLS writer against an in-memory Data Lake endpoint.
+
+mod common;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use common::{
+    datalake::{MockDataLake, Seen},
+    dead_letter_dir, files_below, init, write_options,
+};
+use flate2::read::GzDecoder;
+use mqtt_adls_bridge::{
+    adls::{self, AdlsSink, Destination, WriteJob},
+    format::{Compression, FileFormat},
+    metrics::METRICS,
+    sink::{FileMetadata, LocalSink, Sink},
+    wal::WriteAheadLog,
+};
+use std::{
+    io::Read,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+fn lines(n: usize) -> Vec<String> {
+    (0..n).map(|i| format!(r#"{{"i":{i}}}"#)).collect()
+}
+
+/// The operations and positions of the requests, e.g. `append@0`.
+fn operations(requests: &[Seen]) -> Vec<String> {
+    requests
+        .iter()
+        .map(|seen| match seen.position() {
+            Some(position) => format!("{}@{}", seen.operation(), position),
+            None => seen.operation().to_string(),
+        })
+        .collect()
+}
+
+fn job(path: &str, payload: &str, n_per_file: i32) -> WriteJob {
+    WriteJob {
+        path: path.to_string(),
+        payload: payload.to_string(),
+        n_per_file,
+        ..Default::default()
+    }
+}
+
+/// Run the write loop over `jobs` until they are all written.
+async fn write_all(sink: &dyn Sink, jobs: Vec<WriteJob>, defaults: &Destination) {
+    write_all_with(sink, jobs, &write_options(defaults.clone())).await;
+}
+
+/// Like `write_all`, with `options` for the write loop.
+async fn write_all_with(sink: &dyn Sink, jobs: Vec<WriteJob>, options: &adls::WriteOptions) {
+    let (transmitter, receiver) = mpsc::channel(jobs.len().max(1));
+    for job in jobs {
+        transmitter.try_send(job).unwrap();
+    }
+    drop(transmitter);
+    adls::handle_write_jobs(sink, receiver, None, options)
+        .await
+        .unwrap();
+}
+
+/// A sink that takes a while per file and counts how many files are written
+/// at the same time.
+#[derive(Default)]
+struct SlowSink {
+    writing: AtomicUsize,
+    most_at_once: AtomicUsize,
+    written: AtomicUsize,
+}
+
+#[async_trait]
+impl Sink for SlowSink {
+    async fn write(
+        &self,
+        _container: &str,
+        _file_path: &str,
+        _content: Bytes,
+        _metadata: &FileMetadata,
+    ) -> azure_core::error::Result<()> {
+        let writing = self.writing.fetch_add(1, Ordering::SeqCst) + 1;
+        self.most_at_once.fetch_max(writing, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        self.writing.fetch_sub(1, Ordering::SeqCst);
+        self.written.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+fn defaults(container: &str) -> Destination {
+    Destination {
+        container: Some(container.to_string()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn json_file_is_created_appended_and_flushed() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let sink = AdlsSink::new(datalake.client());
+    let data = lines(3);
+
+    let written = adls::upload_json_multiline(&sink, "raw", "dir/f.json", &data, None)
+        .await
+        .unwrap();
+
+    let content = data.join("\n");
+    assert_eq!(written, content.len());
+    assert_eq!(
+        operations(&datalake.requests()),
+        ["create", "append@0", &format!("flush@{}", content.len())]
+    );
+    let file = &datalake.files()["raw/dir/f.json"];
+    assert_eq!(String::from_utf8_lossy(&file.content), content);
+    assert!(file.uncommitted.is_empty());
+    assert!(file.closed);
+    assert_eq!(file.content_type.as_deref(), Some("application/x-ndjson"));
+    assert_eq!(file.content_encoding, None);
+    assert!(file.properties.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compressed_json_carries_its_encoding() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let sink = AdlsSink::new(datalake.client());
+    let data = lines(2);
+
+    adls::upload_json_multiline(&sink, "raw", "f.json.gz", &data, Some(Compression::Gzip))
+        .await
+        .unwrap();
+
+    let file = &datalake.files()["raw/f.json.gz"];
+    assert_eq!(file.content_encoding.as_deref(), Some("gzip"));
+    let mut content = String::new();
+    GzDecoder::new(file.content.as_slice())
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, data.join("\n"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upload_data_single_appends_at_increasing_offsets() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let data = vec!["ab".to_string(), "cde".to_string(), "f".to_string()];
+
+    adls::upload_data_single(
+        &datalake.client(),
+        "raw".to_string(),
+        "single.txt".to_string(),
+        data,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        operations(&datalake.requests()),
+        ["create", "append@0", "append@2", "append@5", "flush@6"]
+    );
+    let file = &datalake.files()["raw/single.txt"];
+    assert_eq!(file.content, b"abcdef");
+    assert!(file.closed);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_loop_batches_per_path_and_destination() {
+    init();
+    let datalake = MockDataLake::start(&["raw", "curated"]);
+    let sink = AdlsSink::new(datalake.client());
+    let curated = Destination {
+        container: Some("curated".to_string()),
+        prefix: Some("bronze/".to_string()),
+        extension: Some("ndjson".to_string()),
+    };
+    let parquet = FileFormat {
+        format: mqtt_adls_bridge::format::Format::Parquet,
+        ..Default::default()
+    };
+
+    let mut jobs = Vec::new();
+    for i in 0..4 {
+        jobs.push(job("a", &format!(r#"{{"a":{i}}}"#), 2));
+    }
+    jobs.push(job("b", r#"{"b":0}"#, 2));
+    jobs.push(WriteJob {
+        destination: curated.clone(),
+        ..job("a", r#"{"c":0}"#, 1)
+    });
+    jobs.push(WriteJob {
+        format: parquet,
+        ..job("p", r#"{"p":0}"#, 1)
+    });
+    // Jobs without a path match no route and are not written.
+    jobs.push(job("", r#"{"x":0}"#, 1));
+
+    let defaults = Destination {
+        prefix: Some("base".to_string()),
+        ..defaults("raw")
+    };
+    write_all(&sink, jobs, &defaults).await;
+
+    let files = datalake.files();
+    let contents = |dir: &str| -> Vec<String> {
+        let mut contents: Vec<String> = files
+            .iter()
+            .filter(|(name, _)| name.rsplit_once('/').unwrap().0 == dir)
+            .map(|(_, file)| String::from_utf8_lossy(&file.content).into_owned())
+            .collect();
+        contents.sort();
+        contents
+    };
+    assert_eq!(files.len(), 5, "{:?}", files.keys());
+    assert_eq!(
+        contents("raw/base/a"),
+        [
+            "{\"a\":0}\n{\"a\":1}".to_string(),
+            "{\"a\":2}\n{\"a\":3}".to_string()
+        ]
+    );
+    // A batch that is not full is written when the loop stops.
+    assert_eq!(contents("raw/base/b"), [r#"{"b":0}"#]);
+    assert_eq!(contents("curated/bronze/a"), [r#"{"c":0}"#]);
+    assert!(files
+        .keys()
+        .filter(|name| name.starts_with("curated/"))
+        .all(|name| name.ends_with(".ndjson")));
+
+    let (name, file) = files
+        .iter()
+        .find(|(name, _)| name.starts_with("raw/base/p/"))
+        .unwrap();
+    assert!(name.ends_with(".parquet"));
+    assert!(file.content.starts_with(b"PAR1"));
+    assert_eq!(
+        file.content_type.as_deref(),
+        Some("application/vnd.apache.parquet")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_requests_are_returned_as_errors() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let sink = AdlsSink::new(datalake.client());
+    let data = lines(1);
+
+    for (operation, status) in [("create", 403), ("append", 409), ("flush", 412)] {
+        datalake.fail(operation, status, 1);
+        let e = adls::upload_json_multiline(&sink, "raw", operation, &data, None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                e.kind(),
+                azure_core::error::ErrorKind::HttpResponse { status: s, .. } if *s == status
+            ),
+            "{operation}: {e:?}"
+        );
+    }
+
+    // Data that was appended but not flushed is never visible.
+    let file = &datalake.files()["raw/flush"];
+    assert!(file.content.is_empty());
+    assert!(!file.closed);
+
+    // Missing file systems are reported as well.
+    let e = adls::upload_json_multiline(&sink, "missing", "f.json", &data, None)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        e.kind(),
+        azure_core::error::ErrorKind::HttpResponse { status: 404, .. }
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_uploads_are_retried_with_the_same_file_name() {
+    init();
+    let datalake = MockDataLake::start(&["retried"]);
+    let sink = AdlsSink::new(datalake.client());
+    datalake.fail("append", 403, 2);
+
+    write_all(&sink, vec![job("r", r#"{"r":0}"#, 1)], &defaults("retried")).await;
+
+    let requests = datalake.requests();
+    assert_eq!(
+        operations(&requests),
+        ["create", "append@0", "create", "append@0", "create", "append@0", "flush@7"]
+    );
+    let created: Vec<&str> = requests
+        .iter()
+        .filter(|seen| seen.operation() == "create")
+        .map(|seen| seen.path.as_str())
+        .collect();
+    assert!(created.iter().all(|path| *path == created[0]));
+    assert_eq!(datalake.files()[created[0]].content, br#"{"r":0}"#);
+    assert!(files_below(&dead_letter_dir().join("retried")).is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batches_that_cannot_be_uploaded_are_dead_lettered() {
+    init();
+    let datalake = MockDataLake::start(&["dead"]);
+    let sink = AdlsSink::new(datalake.client());
+    datalake.fail("create", 403, usize::MAX);
+
+    write_all(&sink, vec![job("d", r#"{"d":0}"#, 1)], &defaults("dead")).await;
+
+    // Every attempt failed, so nothing is in the datalake.
+    assert_eq!(
+        operations(&datalake.requests()),
+        ["create", "create", "create"]
+    );
+    assert!(datalake.files().is_empty());
+
+    let dead_letters = files_below(&dead_letter_dir().join("dead").join("d"));
+    assert_eq!(dead_letters.len(), 1);
+    assert!(dead_letters[0].extension().is_some_and(|ext| ext == "json"));
+    assert_eq!(
+        std::fs::read_to_string(&dead_letters[0]).unwrap(),
+        r#"{"d":0}"#
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_ahead_log_entries_are_kept_until_a_batch_is_stored() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let sink = AdlsSink::new(datalake.client());
+    datalake.fail("create", 403, usize::MAX);
+    let wal =
+        WriteAheadLog::open(dead_letter_dir().join(format!("wal-{}", Uuid::new_v4()))).unwrap();
+    std::fs::create_dir_all(dead_letter_dir()).unwrap();
+    std::fs::write(dead_letter_dir().join("wal-blocked"), "").unwrap();
+
+    // The first batch is dead-lettered, the second can't be written anywhere.
+    for container in ["wal-dead", "wal-blocked"] {
+        let mut job = job("w", r#"{"w":0}"#, 1);
+        wal.append(&mut job).unwrap();
+        let (transmitter, receiver) = mpsc::channel(1);
+        transmitter.try_send(job).unwrap();
+        drop(transmitter);
+        let options = write_options(defaults(container));
+        let result = adls::handle_write_jobs(&sink, receiver, Some(&wal), &options).await;
+        assert_eq!(result.is_ok(), container == "wal-dead", "{container}");
+    }
+
+    assert_eq!(files_below(&dead_letter_dir().join("wal-dead")).len(), 1);
+    let kept = wal.replay().unwrap();
+    assert_eq!(kept.len(), 1);
+    assert_eq!(kept[0].payload, r#"{"w":0}"#);
+}
+
+#[test]
+fn incomplete_write_ahead_log_entries_are_removed_on_open() {
+    let dir = dead_letter_dir().join(format!("wal-{}", Uuid::new_v4()));
+    let wal = WriteAheadLog::open(&dir).unwrap();
+    let mut kept = job("w", r#"{"w":0}"#, 1);
+    wal.append(&mut kept).unwrap();
+    // A crash between writing and renaming leaves a temporary file.
+    std::fs::write(dir.join("00000000000000000001-crashed.tmp"), "{").unwrap();
+
+    let wal = WriteAheadLog::open(&dir).unwrap();
+
+    assert_eq!(files_below(&dir).len(), 1);
+    assert_eq!(wal.replay().unwrap()[0].wal_id, kept.wal_id);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dead_letter_failures_are_returned() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let sink = AdlsSink::new(datalake.client());
+    let (transmitter, receiver) = mpsc::channel(1);
+    // The full batch fails while the loop still runs, which keeps running
+    // and returns the failure once it ends.
+    transmitter.try_send(job("x", r#"{"x":0}"#, 1)).unwrap();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        drop(transmitter);
+    });
+    datalake.fail("create", 403, usize::MAX);
+    let failed = METRICS.batches_failed.get();
+
+    // A container name that is a file in the dead-letter directory can't be
+    // written to.
+    std::fs::create_dir_all(dead_letter_dir()).unwrap();
+    std::fs::write(dead_letter_dir().join("blocked"), "").unwrap();
+    let options = write_options(defaults("blocked"));
+    let result = adls::handle_write_jobs(&sink, receiver, None, &options).await;
+
+    assert!(result.is_err());
+    // Other tests may fail batches at the same time.
+    assert!(METRICS.batches_failed.get() > failed);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missing_containers_are_reported_or_created() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let sink = AdlsSink::new(datalake.client());
+
+    sink.check_container("raw", false).await.unwrap();
+
+    let e = sink.check_container("new", false).await.unwrap_err();
+    assert!(e.to_string().contains("ADLS_CREATE_CONTAINER"), "{e}");
+    assert!(!datalake.file_systems().contains("new"));
+
+    sink.check_container("new", true).await.unwrap();
+    assert!(datalake.file_systems().contains("new"));
+    sink.check_container("new", false).await.unwrap();
+
+    datalake.fail("get_filesystem", 403, 1);
+    let e = sink.check_container("raw", true).await.unwrap_err();
+    assert!(matches!(
+        e.kind(),
+        azure_core::error::ErrorKind::HttpResponse { status: 403, .. }
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn local_sink_uses_the_same_layout() {
+    init();
+    let root = dead_letter_dir().join("local-sink");
+    let sink = LocalSink::new(&root);
+
+    write_all(
+        &sink,
+        vec![job("l/x=1", r#"{"l":0}"#, 1)],
+        &Destination {
+            prefix: Some("p".to_string()),
+            ..defaults("raw")
+        },
+    )
+    .await;
+
+    let files = files_below(&root.join("raw").join("p").join("l").join("x=1"));
+    assert_eq!(files.len(), 1);
+    assert!(files[0].to_string_lossy().ends_with(".json"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batches_for_different_paths_are_uploaded_concurrently() {
+    init();
+    let jobs = || {
+        (0..8)
+            .map(|i| job(&format!("c/{i}"), r#"{"c":0}"#, 1))
+            .collect()
+    };
+
+    for (concurrency, most_at_once) in [(4, 2..=4), (1, 1..=1)] {
+        let sink = SlowSink::default();
+        let options = adls::WriteOptions {
+            concurrency,
+            ..write_options(defaults("concurrent"))
+        };
+
+        write_all_with(&sink, jobs(), &options).await;
+
+        assert_eq!(sink.written.load(Ordering::SeqCst), 8);
+        let seen = sink.most_at_once.load(Ordering::SeqCst);
+        assert!(most_at_once.contains(&seen), "{concurrency}: {seen}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn local_sink_does_not_write_outside_its_root() {
+    init();
+    let base = dead_letter_dir().join("traversal");
+    let _ = std::fs::remove_dir_all(&base);
+    let root = base.join("root");
+    let sink = LocalSink::new(&root);
+    let metadata = FileMetadata::default();
+
+    for (container, path) in [
+        ("raw", "../../escaped.json"),
+        ("..", "escaped.json"),
+        ("raw", "/tmp/escaped.json"),
+        ("/tmp", "escaped.json"),
+    ] {
+        let result = sink
+            .write(container, path, Bytes::from_static(b"{}"), &metadata)
+            .await;
+        assert!(result.is_err(), "{container} {path}");
+    }
+    assert!(files_below(&base).is_empty());
+    assert!(!std::path::Path::new("/tmp/escaped.json").exists());
+
+    sink.write("raw", "./a/ok.json", Bytes::from_static(b"{}"), &metadata)
+        .await
+        .unwrap();
+    assert_eq!(files_below(&base), [root.join("raw/a/ok.json")]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn partial_batches_are_written_after_the_flush_interval() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let sink = AdlsSink::new(datalake.client());
+    let options = adls::WriteOptions {
+        flush_interval: Some(Duration::from_millis(1500)),
+        ..write_options(defaults("raw"))
+    };
+    let (transmitter, receiver) = mpsc::channel(10);
+    transmitter
+        .try_send(job("quiet", r#"{"q":0}"#, 10))
+        .unwrap();
+    transmitter
+        .try_send(job("quiet", r#"{"q":1}"#, 10))
+        .unwrap();
+
+    // The channel stays open, so only the interval can flush the batch.
+    let check = async {
+        tokio::time::sleep(Duration::from_millis(1000)).await;
+        assert!(datalake.files().is_empty());
+        tokio::time::sleep(Duration::from_millis(2000)).await;
+        let files = datalake.files();
+        assert_eq!(files.len(), 1);
+        let (name, file) = files.iter().next().unwrap();
+        assert!(name.starts_with("raw/quiet/"), "{name}");
+        assert_eq!(file.content, b"{\"q\":0}\n{\"q\":1}");
+    };
+    let write_loop = adls::handle_write_jobs(&sink, receiver, None, &options);
+    tokio::select! {
+        _ = write_loop => panic!("The write loop ended"),
+        _ = check => {}
+    }
+    drop(transmitter);
+}
@@ -0,0 +1,362 @@
+//! Tests of authenticating against Azure Datalake Gen2, with the mock
+//! answering both the storage and the token requests.
+
+mod common;
+
+use common::{datalake::MockDataLake, init};
+use mqtt_adls_bridge::{
+    adls::{self, AdlsSink},
+    auth::AdlsAuth,
+};
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    pkcs12::Pkcs12,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    sign::Verifier,
+    x509::{X509Builder, X509NameBuilder, X509},
+};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+const AUTHORITY_HOST: &str = "https://login.microsoftonline.com/";
+const TOKEN_URL: &str = "https://login.microsoftonline.com/tenant-1/oauth2/v2.0/token";
+const SCOPE: &str = "https://storage.azure.com/.default";
+
+/// Write a file of three lines to `raw/{path}` through a client using `auth`.
+async fn upload(
+    datalake: &MockDataLake,
+    auth: AdlsAuth,
+    path: &str,
+) -> azure_core::error::Result<usize> {
+    let sink = AdlsSink::new(datalake.client_with(auth)?);
+    let lines: Vec<String> = (0..3).map(|i| format!(r#"{{"i":{i}}}"#)).collect();
+    adls::upload_json_multiline(&sink, "raw", path, &lines, None).await
+}
+
+/// The `Authorization` header of every storage request.
+fn authorizations(datalake: &MockDataLake) -> Vec<String> {
+    datalake
+        .requests()
+        .into_iter()
+        .map(|seen| seen.authorization.unwrap_or_default())
+        .collect()
+}
+
+/// A path for test files of this process.
+fn temp_file(name: &str) -> PathBuf {
+    let dir = common::dead_letter_dir().join("auth");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir.join(name)
+}
+
+/// A self-signed certificate and its private key.
+fn certificate() -> (X509, PKey<Private>) {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "mqtt-adls-bridge-test")
+        .unwrap();
+    let name = name.build();
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+    (builder.build(), key)
+}
+
+/// Decode one part of a JWT.
+fn jwt_part(part: &str) -> Value {
+    serde_json::from_slice(&base64::decode_config(part, base64::URL_SAFE_NO_PAD).unwrap()).unwrap()
+}
+
+fn client_secret() -> AdlsAuth {
+    AdlsAuth::ClientSecret {
+        authority_host: AUTHORITY_HOST.to_string(),
+        tenant_id: "tenant-1".to_string(),
+        client_id: "client-1".to_string(),
+        client_secret: "secret & more".to_string(),
+    }
+}
+
+fn client_certificate(certificate_path: &Path, password: &str) -> AdlsAuth {
+    AdlsAuth::ClientCertificate {
+        authority_host: AUTHORITY_HOST.to_string(),
+        tenant_id: "tenant-1".to_string(),
+        client_id: "client-1".to_string(),
+        certificate_path: certificate_path.display().to_string(),
+        password: password.to_string(),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_secret_tokens_are_cached() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+
+    upload(&datalake, client_secret(), "a.json").await.unwrap();
+    upload(&datalake, client_secret(), "b.json").await.unwrap();
+
+    // Every client has a cache of its own.
+    let tokens = datalake.token_requests();
+    assert_eq!(tokens.len(), 2);
+    let token = &tokens[0];
+    assert_eq!(token.method, "POST");
+    assert_eq!(token.path, "tenant-1/oauth2/v2.0/token");
+    assert_eq!(token.form["grant_type"], "client_credentials");
+    assert_eq!(token.form["client_id"], "client-1");
+    assert_eq!(token.form["client_secret"], "secret & more");
+    assert_eq!(token.form["scope"], SCOPE);
+
+    // Create, append and flush per file, all with the token of their client.
+    let authorizations = authorizations(&datalake);
+    assert_eq!(authorizations.len(), 6);
+    assert!(authorizations[..3].iter().all(|a| a == "Bearer token-1"));
+    assert!(authorizations[3..].iter().all(|a| a == "Bearer token-2"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tokens_are_refreshed_shortly_before_they_expire() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+
+    // Five minutes are left, which is the refresh margin.
+    datalake.set_token_lifetime(300);
+    upload(&datalake, client_secret(), "a.json").await.unwrap();
+    assert_eq!(datalake.token_requests().len(), 3);
+    assert_eq!(
+        authorizations(&datalake),
+        ["Bearer token-1", "Bearer token-2", "Bearer token-3"]
+    );
+
+    datalake.set_token_lifetime(360);
+    upload(&datalake, client_secret(), "b.json").await.unwrap();
+    assert_eq!(datalake.token_requests().len(), 4);
+    assert!(authorizations(&datalake)[3..]
+        .iter()
+        .all(|a| a == "Bearer token-4"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn token_errors_fail_the_request() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    datalake.fail("token", 401, 10);
+
+    let e = upload(&datalake, client_secret(), "a.json")
+        .await
+        .unwrap_err();
+
+    assert!(format!("{e:?}").contains("invalid_client"), "{e:?}");
+    assert!(datalake.requests().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_certificates_sign_an_assertion() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let (certificate, key) = certificate();
+    let pem = temp_file("client.pem");
+    let mut content = certificate.to_pem().unwrap();
+    content.extend(key.private_key_to_pem_pkcs8().unwrap());
+    std::fs::write(&pem, content).unwrap();
+
+    let before = chrono::Utc::now().timestamp();
+    upload(&datalake, client_certificate(&pem, ""), "a.json")
+        .await
+        .unwrap();
+
+    let token = &datalake.token_requests()[0];
+    assert_eq!(token.form["grant_type"], "client_credentials");
+    assert_eq!(token.form["client_id"], "client-1");
+    assert_eq!(
+        token.form["client_assertion_type"],
+        "urn:ietf:params:oauth:client-assertion-type:jwt-bearer"
+    );
+    assert_eq!(token.form["scope"], SCOPE);
+    assert!(!token.form.contains_key("client_secret"));
+
+    let assertion = &token.form["client_assertion"];
+    let parts: Vec<&str> = assertion.split('.').collect();
+    assert_eq!(parts.len(), 3);
+    let header = jwt_part(parts[0]);
+    assert_eq!(header["alg"], "RS256");
+    assert_eq!(header["typ"], "JWT");
+    let thumbprint = certificate.digest(MessageDigest::sha1()).unwrap();
+    assert_eq!(
+        header["x5t"],
+        base64::encode_config(thumbprint, base64::URL_SAFE_NO_PAD)
+    );
+
+    let claims = jwt_part(parts[1]);
+    assert_eq!(claims["aud"], TOKEN_URL);
+    assert_eq!(claims["iss"], "client-1");
+    assert_eq!(claims["sub"], "client-1");
+    assert!(uuid::Uuid::parse_str(claims["jti"].as_str().unwrap()).is_ok());
+    let nbf = claims["nbf"].as_i64().unwrap();
+    assert!(nbf >= before && nbf <= chrono::Utc::now().timestamp());
+    assert_eq!(claims["exp"].as_i64().unwrap(), nbf + 600);
+
+    // Signed with the key of the certificate.
+    let signature = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap();
+    let public_key = certificate.public_key().unwrap();
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &public_key).unwrap();
+    verifier
+        .update(format!("{}.{}", parts[0], parts[1]).as_bytes())
+        .unwrap();
+    assert!(verifier.verify(&signature).unwrap());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn client_certificates_may_be_pkcs12_files() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    let (certificate, key) = certificate();
+    let pfx = temp_file("client.pfx");
+    let pkcs12 = Pkcs12::builder()
+        .name("client")
+        .pkey(&key)
+        .cert(&certificate)
+        .build2("pfx password")
+        .unwrap();
+    std::fs::write(&pfx, pkcs12.to_der().unwrap()).unwrap();
+
+    upload(
+        &datalake,
+        client_certificate(&pfx, "pfx password"),
+        "a.json",
+    )
+    .await
+    .unwrap();
+    let assertion = &datalake.token_requests()[0].form["client_assertion"];
+    let thumbprint = certificate.digest(MessageDigest::sha1()).unwrap();
+    assert_eq!(
+        jwt_part(assertion.split('.').next().unwrap())["x5t"],
+        base64::encode_config(thumbprint, base64::URL_SAFE_NO_PAD)
+    );
+
+    for (path, password) in [(&pfx, "wrong"), (&temp_file("missing.pem"), "")] {
+        let e = upload(&datalake, client_certificate(path, password), "b.json")
+            .await
+            .unwrap_err();
+        assert_eq!(*e.kind(), azure_core::error::ErrorKind::Credential, "{e}");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn workload_identity_reads_the_federated_token_for_every_request() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+    // Expired right away, so every storage request needs a new token.
+    datalake.set_token_lifetime(0);
+    let token_file = temp_file("federated-token");
+    std::fs::write(&token_file, "federated-1\n").unwrap();
+    let auth = AdlsAuth::WorkloadIdentity {
+        authority_host: AUTHORITY_HOST.to_string(),
+        tenant_id: "tenant-1".to_string(),
+        client_id: "client-1".to_string(),
+        token_file: token_file.display().to_string(),
+    };
+
+    upload(&datalake, auth.clone(), "a.json").await.unwrap();
+    std::fs::write(&token_file, "federated-2").unwrap();
+    upload(&datalake, auth, "b.json").await.unwrap();
+
+    let assertions: Vec<String> = datalake
+        .token_requests()
+        .iter()
+        .map(|token| {
+            assert_eq!(token.path, "tenant-1/oauth2/v2.0/token");
+            assert_eq!(token.form["client_id"], "client-1");
+            assert_eq!(
+                token.form["client_assertion_type"],
+                "urn:ietf:params:oauth:client-assertion-type:jwt-bearer"
+            );
+            assert_eq!(token.form["scope"], SCOPE);
+            token.form["client_assertion"].clone()
+        })
+        .collect();
+    assert_eq!(
+        assertions,
+        [
+            "federated-1",
+            "federated-1",
+            "federated-1",
+            "federated-2",
+            "federated-2",
+            "federated-2"
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn managed_identities_use_the_metadata_endpoint() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+
+    upload(
+        &datalake,
+        AdlsAuth::ManagedIdentity { client_id: None },
+        "a.json",
+    )
+    .await
+    .unwrap();
+    upload(
+        &datalake,
+        AdlsAuth::ManagedIdentity {
+            client_id: Some("user-assigned".to_string()),
+        },
+        "b.json",
+    )
+    .await
+    .unwrap();
+
+    // The endpoint writes `expires_in` as a string, which is read as a
+    // number, so every client requests one token.
+    let tokens = datalake.token_requests();
+    assert_eq!(tokens.len(), 2);
+    for token in &tokens {
+        assert_eq!(token.method, "GET");
+        assert_eq!(token.path, "metadata/identity/oauth2/token");
+        assert_eq!(token.metadata.as_deref(), Some("true"));
+        assert_eq!(token.query["api-version"], "2018-02-01");
+        assert_eq!(token.query["resource"], "https://storage.azure.com/");
+        assert!(token.form.is_empty());
+    }
+    assert!(!tokens[0].query.contains_key("client_id"));
+    assert_eq!(tokens[1].query["client_id"], "user-assigned");
+    assert!(authorizations(&datalake)[..3]
+        .iter()
+        .all(|a| a == "Bearer token-1"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sas_tokens_are_added_to_the_query() {
+    init();
+    let datalake = MockDataLake::start(&["raw"]);
+
+    let auth = AdlsAuth::Sas {
+        token: "?sv=2022-11-02&sp=rwc&sig=c2lnbmF0dXJl%2B".to_string(),
+    };
+    upload(&datalake, auth, "a.json").await.unwrap();
+
+    let requests = datalake.requests();
+    assert_eq!(requests.len(), 3);
+    for seen in &requests {
+        assert_eq!(seen.authorization, None);
+        assert_eq!(seen.query["sv"], "2022-11-02");
+        assert_eq!(seen.query["sp"], "rwc");
+        assert_eq!(seen.query["sig"], "c2lnbmF0dXJl+");
+    }
+    // Appended to the query of the operation.
+    assert_eq!(requests[1].query["action"], "append");
+    assert!(datalake.token_requests().is_empty());
+}
@@ -0,0 +1,411 @@
+//! A small MQTT broker for tests, speaking enough of MQTT 3.1.1 and 5 for
+//! the bridge: connect, subscribe, unsubscribe, publish with QoS 0 and 1,
+//! ping and disconnect.
+//!
+//! Messages are only sent to the clients that are connected when
+//! `Broker::publish` is called. `Broker::stop` drops every connection and
+//! closes the port, and `Broker::restart` listens on the same port again,
+//! like a broker that was restarted without persistence.
+
+use mqtt_adls_bridge::routing::topic_matches;
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const CONNECT: u8 = 1;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const UNSUBSCRIBE: u8 = 10;
+const PINGREQ: u8 = 12;
+const DISCONNECT: u8 = 14;
+
+/// A message published by the broker.
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    /// MQTT 5 only.
+    pub content_type: Option<String>,
+    /// MQTT 5 only.
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl Message {
+    pub fn new(topic: &str, payload: impl Into<Vec<u8>>) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: payload.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn qos(mut self, qos: u8) -> Message {
+        self.qos = qos;
+        self
+    }
+
+    pub fn retain(mut self) -> Message {
+        self.retain = true;
+        self
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Message {
+        self.content_type = Some(content_type.to_string());
+        self
+    }
+
+    pub fn user_property(mut self, name: &str, value: &str) -> Message {
+        self.user_properties
+            .push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Encode the message as a PUBLISH packet for a client of `version`.
+    pub fn encode(&self, version: u8, packet_id: u16) -> Vec<u8> {
+        let mut body = string(&self.topic);
+        if self.qos > 0 {
+            body.extend(packet_id.to_be_bytes());
+        }
+        if version >= 5 {
+            let mut properties = Vec::new();
+            if let Some(content_type) = &self.content_type {
+                properties.push(0x03);
+                properties.extend(string(content_type));
+            }
+            for (name, value) in &self.user_properties {
+                properties.push(0x26);
+                properties.extend(string(name));
+                properties.extend(string(value));
+            }
+            body.extend(varint(properties.len()));
+            body.extend(properties);
+        }
+        body.extend(&self.payload);
+        let flags = (self.qos << 1) | u8::from(self.retain);
+        packet(PUBLISH << 4 | flags, &body)
+    }
+}
+
+#[derive(Debug)]
+struct Connection {
+    stream: Mutex<TcpStream>,
+    version: u8,
+    subscriptions: Mutex<Vec<String>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    connections: Mutex<Vec<Arc<Connection>>>,
+    /// Incremented by `stop`, which ends the accept loop of the generation.
+    generation: AtomicUsize,
+    connects: AtomicUsize,
+    subscribes: Mutex<Vec<String>>,
+    /// Topic filters whose subscriptions are rejected.
+    rejected: Mutex<Vec<String>>,
+    /// Packet ids acknowledged by clients.
+    acks: Mutex<Vec<u16>>,
+    next_packet_id: AtomicU16,
+}
+
+/// A broker listening on `127.0.0.1`.
+#[derive(Debug, Clone)]
+pub struct Broker {
+    port: u16,
+    state: Arc<State>,
+}
+
+impl Broker {
+    /// Start a broker on a free port.
+    pub fn start() -> Broker {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("A free port is available");
+        let broker = Broker {
+            port: listener.local_addr().unwrap().port(),
+            state: Arc::new(State {
+                next_packet_id: AtomicU16::new(1),
+                ..Default::default()
+            }),
+        };
+        broker.accept(listener);
+        broker
+    }
+
+    /// The URI to connect to, e.g. `tcp://127.0.0.1:1883`.
+    pub fn uri(&self) -> String {
+        format!("tcp://127.0.0.1:{}", self.port)
+    }
+
+    /// Drop every connection and stop listening.
+    pub fn stop(&self) {
+        self.state.generation.fetch_add(1, Ordering::SeqCst);
+        for connection in self.state.connections.lock().unwrap().drain(..) {
+            let _ = connection.stream.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Listen on the same port again after `stop`.
+    pub fn restart(&self) {
+        // The accept loop of the last generation closes the port within a poll.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let listener = loop {
+            match TcpListener::bind(("127.0.0.1", self.port)) {
+                Ok(listener) => break listener,
+                Err(e) if Instant::now() < deadline => {
+                    log::debug!("Waiting for port {}: {}", self.port, e);
+                    thread::sleep(Duration::from_millis(20));
+                }
+                Err(e) => panic!("Port {} did not become free: {}", self.port, e),
+            }
+        };
+        self.accept(listener);
+    }
+
+    /// Send `message` to every connected client subscribed to its topic.
+    pub fn publish(&self, message: &Message) {
+        for connection in self.state.connections.lock().unwrap().iter() {
+            let subscribed = connection
+                .subscriptions
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|filter| topic_matches(filter, &message.topic));
+            if subscribed {
+                let packet_id = self.state.next_packet_id.fetch_add(1, Ordering::SeqCst);
+                let packet = message.encode(connection.version, packet_id);
+                let _ = connection.stream.lock().unwrap().write_all(&packet);
+            }
+        }
+    }
+
+    /// Number of CONNECT packets received so far.
+    pub fn connects(&self) -> usize {
+        self.state.connects.load(Ordering::SeqCst)
+    }
+
+    /// Every topic filter subscribed to so far, in order.
+    pub fn subscribes(&self) -> Vec<String> {
+        self.state.subscribes.lock().unwrap().clone()
+    }
+
+    /// Reject subscriptions to `filter` from now on, with code 0x80.
+    pub fn reject(&self, filter: &str) {
+        self.state.rejected.lock().unwrap().push(filter.to_string());
+    }
+
+    /// Number of QoS 1 messages acknowledged by clients.
+    pub fn acks(&self) -> usize {
+        self.state.acks.lock().unwrap().len()
+    }
+
+    /// Wait until `condition` holds, for at most `timeout`.
+    pub fn wait_until(&self, timeout: Duration, condition: impl Fn(&Broker) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !condition(self) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    fn accept(&self, listener: TcpListener) {
+        listener
+            .set_nonblocking(true)
+            .expect("The listener can be made non-blocking");
+        let generation = self.state.generation.load(Ordering::SeqCst);
+        let state = self.state.clone();
+        thread::spawn(move || {
+            while state.generation.load(Ordering::SeqCst) == generation {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let state = state.clone();
+                        thread::spawn(move || {
+                            if let Err(e) = serve(&state, stream) {
+                                log::debug!("Broker connection closed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(5));
+                    }
+                    Err(e) => panic!("Error accepting a connection: {e}"),
+                }
+            }
+        });
+    }
+}
+
+/// Handle the packets of one client until it disconnects.
+fn serve(state: &State, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    let (header, body) = read_packet(&mut stream)?;
+    if header >> 4 != CONNECT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Expected CONNECT",
+        ));
+    }
+    // Protocol name, then the protocol level: 4 for 3.1.1 and 5 for MQTT 5.
+    let version = body[2 + u16::from_be_bytes([body[0], body[1]]) as usize];
+    state.connects.fetch_add(1, Ordering::SeqCst);
+    let connack: &[u8] = if version >= 5 { &[0, 0, 0] } else { &[0, 0] };
+    stream.write_all(&packet(0x20, connack))?;
+
+    let connection = Arc::new(Connection {
+        stream: Mutex::new(stream.try_clone()?),
+        version,
+        subscriptions: Mutex::new(Vec::new()),
+    });
+    state.connections.lock().unwrap().push(connection.clone());
+
+    loop {
+        let (header, body) = read_packet(&mut stream)?;
+        match header >> 4 {
+            SUBSCRIBE => {
+                let mut i = 2;
+                if version >= 5 {
+                    let (length, used) = read_varint(&body[i..]);
+                    i += used + length;
+                }
+                let mut codes = Vec::new();
+                while i < body.len() {
+                    let length = u16::from_be_bytes([body[i], body[i + 1]]) as usize;
+                    let filter = String::from_utf8_lossy(&body[i + 2..i + 2 + length]).into_owned();
+                    let qos = body[i + 2 + length] & 3;
+                    i += 3 + length;
+                    state.subscribes.lock().unwrap().push(filter.clone());
+                    if state.rejected.lock().unwrap().contains(&filter) {
+                        codes.push(0x80);
+                        continue;
+                    }
+                    connection.subscriptions.lock().unwrap().push(filter);
+                    codes.push(qos);
+                }
+                let mut suback = body[..2].to_vec();
+                if version >= 5 {
+                    suback.push(0);
+                }
+                suback.extend(codes);
+                connection
+                    .stream
+                    .lock()
+                    .unwrap()
+                    .write_all(&packet(0x90, &suback))?;
+            }
+            UNSUBSCRIBE => {
+                // Only acknowledged, MQTT 5 with "no subscription existed".
+                let mut unsuback = body[..2].to_vec();
+                if version >= 5 {
+                    unsuback.extend([0, 0x11]);
+                }
+                connection
+                    .stream
+                    .lock()
+                    .unwrap()
+                    .write_all(&packet(0xb0, &unsuback))?;
+            }
+            PUBLISH => {
+                // Messages from the bridge, e.g. dead letters, are only acknowledged.
+                let qos = (header >> 1) & 3;
+                if qos > 0 {
+                    let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let packet_id = &body[2 + length..4 + length];
+                    connection
+                        .stream
+                        .lock()
+                        .unwrap()
+                        .write_all(&packet(PUBACK << 4, packet_id))?;
+                }
+            }
+            PUBACK => {
+                let packet_id = u16::from_be_bytes([body[0], body[1]]);
+                state.acks.lock().unwrap().push(packet_id);
+            }
+            PINGREQ => connection
+                .stream
+                .lock()
+                .unwrap()
+                .write_all(&packet(0xd0, &[]))?,
+            DISCONNECT => {
+                state
+                    .connections
+                    .lock()
+                    .unwrap()
+                    .retain(|c| !Arc::ptr_eq(c, &connection));
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0; 1];
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+    let mut length = 0;
+    let mut multiplier = 1;
+    loop {
+        stream.read_exact(&mut byte)?;
+        length += (byte[0] & 127) as usize * multiplier;
+        multiplier *= 128;
+        if byte[0] & 128 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+/// Decode a variable byte integer, returning it and the bytes it took.
+fn read_varint(bytes: &[u8]) -> (usize, usize) {
+    let mut value = 0;
+    let mut multiplier = 1;
+    for (i, byte) in bytes.iter().enumerate() {
+        value += (byte & 127) as usize * multiplier;
+        multiplier *= 128;
+        if byte & 128 == 0 {
+            return (value, i + 1);
+        }
+    }
+    (value, bytes.len())
+}
+
+fn varint(mut n: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let mut byte = (n % 128) as u8;
+        n /= 128;
+        if n > 0 {
+            byte |= 128;
+        }
+        out.push(byte);
+        if n == 0 {
+            return out;
+        }
+    }
+}
+
+fn string(s: &str) -> Vec<u8> {
+    let mut out = (s.len() as u16).to_be_bytes().to_vec();
+    out.extend(s.as_bytes());
+    out
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![header];
+    out.extend(varint(body.len()));
+    out.extend(body);
+    out
+}
@@ -0,0 +1,422 @@
+//! An in-memory stand-in for the Azure Datalake Gen2 (DFS) endpoint.
+//!
+//! `DataLakeClient` always talks to `https://{account}.dfs.core.windows.net`,
+//! so the client returned by `MockDataLake::client` sends its requests through
+//! `Redirect`, which points them at a local server instead. The server keeps
+//! file systems and files in memory and checks append and flush positions
+//! the way the real service does. It also hands out tokens as the Azure AD
+//! token endpoint and the managed identity endpoint would, since the
+//! credentials send their requests through the same client.
+
+use async_trait::async_trait;
+use azure_core::{HttpClient, Request as AzureRequest, Response as AzureResponse};
+use azure_storage_datalake::prelude::DataLakeClient;
+use bytes::Bytes;
+use chrono::Utc;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use mqtt_adls_bridge::auth::AdlsAuth;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// The well-known key of the storage emulator. The mock does not check
+/// signatures, but the client needs a valid base64 key to sign requests.
+const ACCOUNT_KEY: &str =
+    "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+
+/// A file in the mock.
+#[derive(Debug, Clone, Default)]
+pub struct MockFile {
+    /// Data made visible by a flush.
+    pub content: Vec<u8>,
+    /// Data appended since the last flush.
+    pub uncommitted: Vec<u8>,
+    /// Properties given when the file was created, decoded.
+    pub properties: BTreeMap<String, String>,
+    /// `x-ms-content-type` and `x-ms-content-encoding` given on create.
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    /// Whether the last flush closed the file.
+    pub closed: bool,
+}
+
+/// A request as seen by the mock, e.g. `PATCH raw/dir/f.json action=flush position=12`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Seen {
+    pub method: Method,
+    /// `{file system}/{path}`, decoded.
+    pub path: String,
+    pub query: BTreeMap<String, String>,
+    pub authorization: Option<String>,
+}
+
+/// A token request as seen by the mock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenRequest {
+    pub method: Method,
+    /// E.g. `{tenant}/oauth2/v2.0/token`, without the authority host.
+    pub path: String,
+    pub query: BTreeMap<String, String>,
+    /// The fields of a form body.
+    pub form: BTreeMap<String, String>,
+    /// The `Metadata` header the managed identity endpoint requires.
+    pub metadata: Option<String>,
+}
+
+impl Seen {
+    /// The operation of the request: `create_filesystem`, `get_filesystem`,
+    /// `create`, `append`, `flush` or `other`.
+    pub fn operation(&self) -> &'static str {
+        let query = |key: &str| self.query.get(key).map(String::as_str);
+        match (&self.method, query("resource"), query("action")) {
+            (&Method::PUT, Some("filesystem"), _) => "create_filesystem",
+            (&Method::HEAD | &Method::GET, Some("filesystem"), _) => "get_filesystem",
+            (&Method::PUT, Some("file"), _) => "create",
+            (&Method::PATCH, _, Some("append")) => "append",
+            (&Method::PATCH, _, Some("flush")) => "flush",
+            _ => "other",
+        }
+    }
+
+    pub fn position(&self) -> Option<usize> {
+        self.query.get("position")?.parse().ok()
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    file_systems: BTreeSet<String>,
+    /// Files by `{file system}/{path}`.
+    files: BTreeMap<String, MockFile>,
+    requests: Vec<Seen>,
+    token_requests: Vec<TokenRequest>,
+    /// `expires_in` of the tokens handed out.
+    token_lifetime_secs: i64,
+    /// Status to answer the next requests of an operation with, and how
+    /// many more times to do so.
+    failures: HashMap<&'static str, (u16, usize)>,
+}
+
+/// A running mock of a storage account.
+#[derive(Debug, Clone)]
+pub struct MockDataLake {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl MockDataLake {
+    /// Start a mock with the given file systems on a free port.
+    pub fn start(file_systems: &[&str]) -> MockDataLake {
+        let state = Arc::new(Mutex::new(State {
+            file_systems: file_systems.iter().map(|fs| fs.to_string()).collect(),
+            token_lifetime_secs: 3600,
+            ..Default::default()
+        }));
+        let shared = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = shared.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    handle_request(state.clone(), request)
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        MockDataLake { addr, state }
+    }
+
+    /// A client for the mock, authenticating with a shared key.
+    pub fn client(&self) -> DataLakeClient {
+        self.client_with(AdlsAuth::SharedKey {
+            account_key: ACCOUNT_KEY.to_string(),
+        })
+        .expect("A shared key client can be created")
+    }
+
+    /// A client for the mock, authenticating with `auth`. Token requests are
+    /// answered by the mock as well.
+    pub fn client_with(&self, auth: AdlsAuth) -> azure_core::error::Result<DataLakeClient> {
+        auth.data_lake_client(
+            "devstoreaccount1".to_string(),
+            Arc::new(Redirect(self.addr)),
+        )
+    }
+
+    /// Hand out tokens that expire after `secs`.
+    pub fn set_token_lifetime(&self, secs: i64) {
+        self.state.lock().unwrap().token_lifetime_secs = secs;
+    }
+
+    /// Answer the next `times` requests of `operation` with `status`. Token
+    /// requests are the operation `token`.
+    pub fn fail(&self, operation: &'static str, status: u16, times: usize) {
+        self.state
+            .lock()
+            .unwrap()
+            .failures
+            .insert(operation, (status, times));
+    }
+
+    pub fn file_systems(&self) -> BTreeSet<String> {
+        self.state.lock().unwrap().file_systems.clone()
+    }
+
+    /// Files by `{file system}/{path}`.
+    pub fn files(&self) -> BTreeMap<String, MockFile> {
+        self.state.lock().unwrap().files.clone()
+    }
+
+    /// Every request received so far, in order.
+    pub fn requests(&self) -> Vec<Seen> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Every token request received so far, in order.
+    pub fn token_requests(&self) -> Vec<TokenRequest> {
+        self.state.lock().unwrap().token_requests.clone()
+    }
+}
+
+/// Sends the requests of a client to the mock at the given address.
+#[derive(Debug)]
+struct Redirect(SocketAddr);
+
+#[async_trait]
+impl HttpClient for Redirect {
+    async fn execute_request(
+        &self,
+        _: hyper::http::Request<Bytes>,
+    ) -> azure_core::error::Result<hyper::http::Response<Bytes>> {
+        unimplemented!("The storage clients only use execute_request2")
+    }
+
+    async fn execute_request2(
+        &self,
+        request: &AzureRequest,
+    ) -> azure_core::error::Result<AzureResponse> {
+        let path_and_query = request
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+        let uri = format!("http://{}{}", self.0, path_and_query)
+            .parse()
+            .expect("The redirected URI is valid");
+        let mut redirected = AzureRequest::new(uri, request.method());
+        for (name, value) in request.headers().iter() {
+            redirected.headers_mut().insert(name.clone(), value.clone());
+        }
+        redirected.set_body(request.body().clone());
+        azure_core::new_http_client()
+            .execute_request2(&redirected)
+            .await
+    }
+}
+
+async fn handle_request(
+    state: Arc<Mutex<State>>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+    let header = |name: &str| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let seen = Seen {
+        method: parts.method.clone(),
+        path: percent_decode(parts.uri.path().trim_start_matches('/')),
+        query: url::form_urlencoded::parse(parts.uri.query().unwrap_or("").as_bytes())
+            .into_owned()
+            .collect(),
+        authorization: header("authorization"),
+    };
+    if seen.path.ends_with("oauth2/v2.0/token") || seen.path.ends_with("identity/oauth2/token") {
+        let request = TokenRequest {
+            method: seen.method,
+            path: seen.path,
+            query: seen.query,
+            form: url::form_urlencoded::parse(&body).into_owned().collect(),
+            metadata: header("metadata"),
+        };
+        return Ok(token(&mut state.lock().unwrap(), request));
+    }
+    let properties = parts
+        .headers
+        .get("x-ms-properties")
+        .and_then(|value| value.to_str().ok())
+        .map(decode_properties)
+        .unwrap_or_default();
+
+    let mut state = state.lock().unwrap();
+    state.requests.push(seen.clone());
+
+    let operation = seen.operation();
+    if let Some((status, times)) = state.failures.get_mut(operation) {
+        if *times > 0 {
+            *times -= 1;
+            return Ok(error(*status, "InjectedFailure"));
+        }
+    }
+
+    let file_system = seen.path.split('/').next().unwrap_or_default().to_string();
+    if operation != "create_filesystem" && !state.file_systems.contains(&file_system) {
+        return Ok(error(404, "FilesystemNotFound"));
+    }
+
+    let response = match operation {
+        "create_filesystem" if !state.file_systems.insert(file_system) => {
+            error(409, "FilesystemAlreadyExists")
+        }
+        "create_filesystem" => success(StatusCode::CREATED),
+        "get_filesystem" => success(StatusCode::OK),
+        "create" => {
+            state.files.insert(
+                seen.path.clone(),
+                MockFile {
+                    properties,
+                    content_type: header("x-ms-content-type"),
+                    content_encoding: header("x-ms-content-encoding"),
+                    ..Default::default()
+                },
+            );
+            success(StatusCode::CREATED)
+        }
+        "append" | "flush" => {
+            let Some(file) = state.files.get_mut(&seen.path) else {
+                return Ok(error(404, "PathNotFound"));
+            };
+            let end = file.content.len() + file.uncommitted.len();
+            if operation == "append" {
+                if seen.position() != Some(end) {
+                    return Ok(error(400, "InvalidAppendPosition"));
+                }
+                file.uncommitted.extend_from_slice(&body);
+                success(StatusCode::ACCEPTED)
+            } else {
+                if seen.position() != Some(end) {
+                    return Ok(error(400, "InvalidFlushPosition"));
+                }
+                let uncommitted = std::mem::take(&mut file.uncommitted);
+                file.content.extend(uncommitted);
+                file.closed = seen.query.get("close").map(String::as_str) == Some("true");
+                success(StatusCode::OK)
+            }
+        }
+        _ => error(400, "UnsupportedOperation"),
+    };
+    Ok(response)
+}
+
+/// Hand out a new token, numbered from 1, e.g. `token-1`.
+///
+/// The managed identity endpoint writes numbers as strings.
+fn token(state: &mut State, request: TokenRequest) -> Response<Body> {
+    let managed_identity = request.path.starts_with("metadata/");
+    state.token_requests.push(request);
+    if let Some((status, times)) = state.failures.get_mut("token") {
+        if *times > 0 {
+            *times -= 1;
+            return Response::builder()
+                .status(*status)
+                .body(Body::from(r#"{"error":"invalid_client"}"#))
+                .expect("The response is valid");
+        }
+    }
+
+    let access_token = format!("token-{}", state.token_requests.len());
+    let expires_in = state.token_lifetime_secs;
+    let body = if managed_identity {
+        serde_json::json!({
+            "access_token": access_token,
+            "expires_in": expires_in.to_string(),
+            "token_type": "Bearer",
+        })
+    } else {
+        serde_json::json!({
+            "access_token": access_token,
+            "expires_in": expires_in,
+            "token_type": "Bearer",
+        })
+    };
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("The response is valid")
+}
+
+/// A response with the headers the storage clients expect on every success.
+fn success(status: StatusCode) -> Response<Body> {
+    let now = Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    Response::builder()
+        .status(status)
+        .header("x-ms-request-id", uuid::Uuid::new_v4().to_string())
+        .header("x-ms-version", "2019-12-12")
+        .header("server", "mock-datalake")
+        .header("date", &now)
+        .header("last-modified", &now)
+        .header("etag", "\"0x1\"")
+        .header("x-ms-namespace-enabled", "true")
+        .header("x-ms-properties", "")
+        .body(Body::empty())
+        .expect("The response is valid")
+}
+
+fn error(status: u16, code: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("x-ms-request-id", uuid::Uuid::new_v4().to_string())
+        .header("x-ms-error-code", code)
+        .header("content-type", "application/json")
+        .body(Body::from(format!(
+            r#"{{"error":{{"code":"{code}","message":"Mock error"}}}}"#
+        )))
+        .expect("The response is valid")
+}
+
+/// Decode `x-ms-properties`, e.g. `content_type=YXBwbGljYXRpb24vanNvbg==`.
+fn decode_properties(header: &str) -> BTreeMap<String, String> {
+    header
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .filter_map(|(key, value)| {
+            let value = base64::decode(value).ok()?;
+            Some((key.to_string(), String::from_utf8(value).ok()?))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
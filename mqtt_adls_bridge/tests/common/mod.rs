@@ -0,0 +1,61 @@
+//! Helpers shared by the integration tests.
+// Each test binary uses only some of the helpers.
+#![allow(dead_code)]
+
+pub mod broker;
+pub mod datalake;
+
+use mqtt_adls_bridge::{
+    adls::{Destination, WriteOptions},
+    retry::RetryPolicy,
+};
+use std::{path::PathBuf, sync::Once, time::Duration};
+
+static INIT: Once = Once::new();
+
+/// Log like the bridge does, once per test binary.
+pub fn init() {
+    INIT.call_once(|| {
+        let _ = env_logger::builder().is_test(true).try_init();
+    });
+}
+
+/// Options for the write loop in tests: fast retries, no time-based flushing
+/// and a dead-letter directory of its own.
+pub fn write_options(defaults: Destination) -> WriteOptions {
+    WriteOptions {
+        defaults,
+        flush_interval: None,
+        retry: RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(20),
+        },
+        dead_letter_dir: dead_letter_dir(),
+        concurrency: 4,
+    }
+}
+
+/// Where batches go that could not be uploaded. Tests use a container of
+/// their own, which is the first directory below it.
+pub fn dead_letter_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("mqtt-adls-bridge-tests-{}", std::process::id()))
+}
+
+/// Every file below `dir`, recursively.
+pub fn files_below(dir: &std::path::Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return files;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            files.extend(files_below(&path));
+        } else {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
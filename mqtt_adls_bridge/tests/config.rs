@@ -0,0 +1,274 @@
+//! Tests of loading the configuration from files and variables.
+
+mod common;
+
+use mqtt_adls_bridge::{
+    auth::AuthMode, config::BridgeConfig, dead_letter::DeadLetterTarget, mqtt::MqttVersion,
+    sink::SinkKind,
+};
+use std::{collections::HashMap, fs, path::PathBuf, process::Command};
+
+/// Write `content` to a file of its own in the temporary directory.
+fn config_file(name: &str, content: &str) -> PathBuf {
+    let dir = common::dead_letter_dir().join("config");
+    fs::create_dir_all(&dir).unwrap();
+    let file = dir.join(name);
+    fs::write(&file, content).unwrap();
+    file
+}
+
+/// Load `file` with `vars` as the environment.
+fn load(file: Option<&PathBuf>, vars: &[(&str, &str)]) -> Result<BridgeConfig, Vec<String>> {
+    let vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    BridgeConfig::load_with(file.map(PathBuf::as_path), |key| vars.get(key).cloned())
+        .map_err(|e| e.problems)
+}
+
+const LOCAL: [(&str, &str); 1] = [("SINK", "local")];
+
+#[test]
+fn defaults_need_only_a_sink() {
+    let config = load(None, &LOCAL).unwrap();
+    assert_eq!(
+        config,
+        BridgeConfig {
+            sink: SinkKind::Local,
+            ..Default::default()
+        }
+    );
+    assert_eq!(
+        config.mqtt.connect_options().lwt_payload,
+        "Last will for rust_client"
+    );
+}
+
+#[test]
+fn variables_override_the_file() {
+    let file = config_file(
+        "override.yaml",
+        r#"
+sink: local
+mqtt:
+  broker: tcp://from-file:1883
+  version: 5
+  topics: ["packml/#:2", "service/#"]
+adls:
+  container: curated
+  prefix: bronze
+"#,
+    );
+
+    let config = load(
+        Some(&file),
+        &[
+            ("MQTT_BROKER", "tcp://from-env:1883"),
+            ("ADLS_PREFIX", ""),
+            ("ENVELOPE", "yes"),
+        ],
+    )
+    .unwrap();
+
+    assert_eq!(config.mqtt.broker, "tcp://from-env:1883");
+    assert_eq!(config.mqtt.version, MqttVersion::V5);
+    assert_eq!(config.mqtt.subscriptions().unwrap().topics.len(), 2);
+    assert_eq!(config.adls.container, "curated");
+    assert_eq!(config.adls.prefix, "");
+    assert!(config.envelope.enabled);
+}
+
+#[test]
+fn toml_and_yaml_files_are_equivalent() {
+    let toml = config_file(
+        "equivalent.toml",
+        r#"
+sink = "local"
+flush_interval_secs = 5
+
+[adls]
+auth = "managed_identity"
+
+[retry]
+max_attempts = 2
+"#,
+    );
+    let yaml = config_file(
+        "equivalent.yml",
+        r#"
+sink: local
+flush_interval_secs: 5
+adls:
+  auth: managed_identity
+retry:
+  max_attempts: 2
+"#,
+    );
+
+    let config = load(Some(&toml), &[]).unwrap();
+    assert_eq!(config, load(Some(&yaml), &[]).unwrap());
+    assert_eq!(config.adls.auth, AuthMode::ManagedIdentity);
+    assert_eq!(config.retry.max_attempts, 2);
+}
+
+#[test]
+fn every_problem_is_reported() {
+    let problems = load(
+        None,
+        &[
+            ("SINK", "s3"),
+            ("RETRY_MAX_ATTEMPTS", "many"),
+            ("MQTT_TOPICS", "packml/#:7"),
+            ("ADLS_CONTAINER", "Raw"),
+            ("MQTT_PERSISTENT_SESSION", "true"),
+            ("HTTP_ADDR", "nowhere"),
+        ],
+    )
+    .unwrap_err();
+
+    // A variable that can't be parsed doesn't stop the others from being
+    // checked. The default sink needs an account, so that is reported too.
+    let expected = [
+        "SINK",
+        "RETRY_MAX_ATTEMPTS",
+        "MQTT_TOPICS",
+        "container 'Raw'",
+        "WAL_DIR",
+        "HTTP_ADDR",
+        "ADLSGEN2_STORAGE_ACCOUNT_NAME",
+        "ADLSGEN2_STORAGE_ACCOUNT_KEY",
+    ];
+    assert_eq!(problems.len(), expected.len(), "{problems:#?}");
+    for expected in expected {
+        assert!(
+            problems.iter().any(|problem| problem.contains(expected)),
+            "No problem mentions {expected}: {problems:#?}"
+        );
+    }
+}
+
+#[test]
+fn unknown_keys_in_the_file_are_rejected() {
+    let file = config_file("unknown.toml", "[mqtt]\nbrokr = \"tcp://typo:1883\"\n");
+
+    let problems = load(Some(&file), &LOCAL).unwrap_err();
+
+    assert_eq!(problems.len(), 1);
+    assert!(
+        problems[0].contains("unknown field `brokr`"),
+        "{}",
+        problems[0]
+    );
+}
+
+#[test]
+fn printed_configuration_hides_secrets_and_loads_again() {
+    let vars = [
+        ("SINK", "local"),
+        ("MQTT_PASSWORD", "hunter2"),
+        ("ADLSGEN2_STORAGE_ACCOUNT_KEY", "c2VjcmV0"),
+        ("AZURE_CLIENT_ID", "not-a-secret"),
+    ];
+    let config = load(None, &vars).unwrap();
+
+    let printed = config.masked().to_toml();
+
+    assert!(!printed.contains("hunter2") && !printed.contains("c2VjcmV0"));
+    assert!(printed.contains("not-a-secret"));
+    // Unset secrets stay empty, so it is visible that they are missing.
+    assert!(printed.contains("sas_token = \"\""));
+    let file = config_file("printed.toml", &printed);
+    assert_eq!(load(Some(&file), &[]).unwrap(), config.masked());
+}
+
+#[test]
+fn print_config_is_kept_as_an_alias() {
+    let dir = common::dead_letter_dir();
+    fs::create_dir_all(&dir).unwrap();
+    let print = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_mqtt_adls_bridge"))
+            .args(args)
+            .current_dir(&dir)
+            .env_remove("CONFIG_FILE")
+            .env("SINK", "local")
+            .env("MQTT_PASSWORD", "hunter2")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{args:?}: {output:?}");
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let printed = print(&["validate-config", "--print"]);
+    assert!(printed.contains("password = \"********\""), "{printed}");
+    assert_eq!(print(&["--print-config"]), printed);
+}
+
+#[test]
+fn dead_letters_are_written_in_batches() {
+    let n_per_file = |vars: &[(&str, &str)]| match load(None, vars).unwrap().dead_letter_target() {
+        DeadLetterTarget::Path { path, n_per_file } if path == "dead-letter" => n_per_file,
+        target => panic!("{target:?}"),
+    };
+    assert_eq!(n_per_file(&LOCAL), 100);
+    assert_eq!(
+        n_per_file(&[("SINK", "local"), ("DEAD_LETTER_N_PER_FILE", "5")]),
+        5
+    );
+
+    let problems = load(None, &[("SINK", "local"), ("DEAD_LETTER_N_PER_FILE", "0")]).unwrap_err();
+    assert_eq!(problems.len(), 1);
+    assert!(
+        problems[0].contains("DEAD_LETTER_N_PER_FILE"),
+        "{problems:?}"
+    );
+}
+
+#[test]
+fn tls_settings_are_used_only_for_tls_brokers() {
+    let ca_file = config_file("ca.pem", "");
+    let ca_file = ca_file.to_str().unwrap();
+    let missing = common::dead_letter_dir().join("missing.pem");
+    let missing = missing.to_str().unwrap();
+
+    // A `tcp://` broker ignores the TLS settings, even if the files are missing.
+    let config = load(
+        None,
+        &[
+            ("SINK", "local"),
+            ("MQTT_BROKER", "tcp://localhost:1883"),
+            ("MQTT_TLS_CA_FILE", missing),
+            ("MQTT_TLS_CLIENT_CERT", missing),
+        ],
+    )
+    .unwrap();
+    assert!(!config.mqtt.connect_options().uses_tls());
+
+    for broker in ["ssl://localhost:8883", "mqtts://localhost:8883"] {
+        let config = load(
+            None,
+            &[
+                ("SINK", "local"),
+                ("MQTT_BROKER", broker),
+                ("MQTT_TLS_CA_FILE", ca_file),
+            ],
+        )
+        .unwrap();
+        let connect_options = config.mqtt.connect_options();
+        assert!(connect_options.uses_tls(), "{broker}");
+        assert!(connect_options.ssl_options().is_ok(), "{broker}");
+
+        for key in ["MQTT_TLS_CA_FILE", "MQTT_TLS_CLIENT_CERT"] {
+            let problems = load(
+                None,
+                &[("SINK", "local"), ("MQTT_BROKER", broker), (key, missing)],
+            )
+            .unwrap_err();
+            assert_eq!(problems.len(), 1, "{problems:?}");
+            assert!(
+                problems[0].contains(key) && problems[0].contains(missing),
+                "{problems:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Tests of parsing event times from payloads and partitioning by them.
+
+use chrono::{DateTime, Utc};
+use mqtt_adls_bridge::{
+    event_time::{EventTime, TimeFormat},
+    routing::{MessageProperties, Router},
+};
+use serde_json::{json, Value};
+
+fn event_time(format: &str, timezone: &str) -> EventTime {
+    EventTime {
+        field: Some("/time".to_string()),
+        format: TimeFormat::from(format.to_string()),
+        timezone: timezone.parse().unwrap(),
+    }
+}
+
+/// `value` parsed as `format` in UTC, written as RFC 3339 with milliseconds.
+fn parse(format: &str, value: Value) -> Option<String> {
+    event_time(format, "UTC")
+        .parse(&value)
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+}
+
+#[test]
+fn rfc3339_times_keep_their_offset_or_use_the_timezone() {
+    let copenhagen = event_time("rfc3339", "Europe/Copenhagen");
+    for (value, expected) in [
+        ("2024-05-01T12:00:00Z", "2024-05-01T12:00:00.000Z"),
+        ("2024-05-01T14:00:00.250+02:00", "2024-05-01T12:00:00.250Z"),
+        // Without an offset the time is local to the time zone.
+        ("2024-05-01T14:00:00", "2024-05-01T12:00:00.000Z"),
+        ("2024-01-01 13:00:00.5", "2024-01-01T12:00:00.500Z"),
+    ] {
+        assert_eq!(
+            copenhagen.parse(&json!(value)),
+            Some(expected.parse::<DateTime<Utc>>().unwrap()),
+            "{value}"
+        );
+    }
+    for value in [json!("yesterday"), json!("2024-13-01T00:00:00Z"), json!(1)] {
+        assert_eq!(copenhagen.parse(&value), None, "{value}");
+    }
+}
+
+#[test]
+fn unix_times_are_floored_before_the_epoch() {
+    for (value, expected) in [
+        (json!(1714564800), "2024-05-01T12:00:00.000Z"),
+        (json!("1714564800"), "2024-05-01T12:00:00.000Z"),
+        (json!(1714564800.25), "2024-05-01T12:00:00.250Z"),
+        (json!(0), "1970-01-01T00:00:00.000Z"),
+        (json!(-1), "1969-12-31T23:59:59.000Z"),
+        (json!(-1.5), "1969-12-31T23:59:58.500Z"),
+        (json!("-0.25"), "1969-12-31T23:59:59.750Z"),
+        // Rounding to the nanosecond carries over into the next second.
+        (json!(0.9999999999), "1970-01-01T00:00:01.000Z"),
+    ] {
+        assert_eq!(
+            parse("unix", value.clone()).as_deref(),
+            Some(expected),
+            "{value}"
+        );
+    }
+    for value in [json!("soon"), json!(true), json!(1e300), json!(i64::MAX)] {
+        assert_eq!(parse("unix", value.clone()), None, "{value}");
+    }
+}
+
+#[test]
+fn unix_ms_times_are_floored_before_the_epoch() {
+    for (value, expected) in [
+        (json!(1714564800123_i64), "2024-05-01T12:00:00.123Z"),
+        (json!("1714564800123"), "2024-05-01T12:00:00.123Z"),
+        (json!(-1), "1969-12-31T23:59:59.999Z"),
+        (json!(-1001), "1969-12-31T23:59:58.999Z"),
+        (json!(-1.5), "1969-12-31T23:59:59.998Z"),
+        (json!(1500.75), "1970-01-01T00:00:01.500Z"),
+    ] {
+        assert_eq!(
+            parse("unix_ms", value.clone()).as_deref(),
+            Some(expected),
+            "{value}"
+        );
+    }
+    let precise = event_time("unix_ms", "UTC").parse(&json!(-1.5)).unwrap();
+    assert_eq!(precise.timestamp_nanos_opt(), Some(-1_500_000));
+    assert_eq!(parse("unix_ms", json!(i64::MAX)), None);
+}
+
+#[test]
+fn strftime_patterns_parse_dates_and_times() {
+    let copenhagen = event_time("%d/%m/%Y %H:%M:%S", "Europe/Copenhagen");
+    assert_eq!(
+        copenhagen.parse(&json!("01/05/2024 14:00:00")),
+        Some("2024-05-01T12:00:00Z".parse().unwrap())
+    );
+    // A pattern with an offset ignores the time zone.
+    let offset = event_time("%Y-%m-%d %H:%M %z", "Europe/Copenhagen");
+    assert_eq!(
+        offset.parse(&json!("2024-05-01 12:00 +0000")),
+        Some("2024-05-01T12:00:00Z".parse().unwrap())
+    );
+    // A date alone is midnight in the time zone.
+    let date = event_time("%Y%m%d", "Europe/Copenhagen");
+    assert_eq!(
+        date.parse(&json!(20240501)),
+        Some("2024-04-30T22:00:00Z".parse().unwrap())
+    );
+    assert_eq!(copenhagen.parse(&json!("2024-05-01T12:00:00Z")), None);
+
+    assert!(copenhagen.validate().is_ok());
+    assert!(event_time("%Y-%!", "UTC").validate().is_err());
+    let mut unprefixed = event_time("unix", "UTC");
+    unprefixed.field = Some("time".to_string());
+    assert!(unprefixed.validate().is_err());
+}
+
+#[test]
+fn local_times_around_daylight_saving_changes() {
+    let copenhagen = event_time("rfc3339", "Europe/Copenhagen");
+    // Clocks are turned back from 03:00 to 02:00, so 02:30 happens twice.
+    // The earlier one, still in summer time, is taken.
+    assert_eq!(
+        copenhagen.parse(&json!("2024-10-27T02:30:00")),
+        Some("2024-10-27T00:30:00Z".parse().unwrap())
+    );
+    // Clocks are turned forward from 02:00 to 03:00, so 02:30 never happens.
+    assert_eq!(copenhagen.parse(&json!("2024-03-31T02:30:00")), None);
+}
+
+#[test]
+fn partitions_use_the_local_time_across_daylight_saving_changes() {
+    let router = Router::from_yaml(
+        r#"
+routes:
+  - name: events
+    topic: "events/#"
+    time:
+      field: /time
+      timezone: Europe/Copenhagen
+    path: "events/{yyyy}/{mm}/{dd}/{hh}"
+"#,
+    )
+    .unwrap();
+    let received: DateTime<Utc> = "2024-05-01T12:00:00Z".parse().unwrap();
+    let path = |time: &str| {
+        router
+            .route(
+                "events/a",
+                &json!({ "time": time }),
+                &MessageProperties::default(),
+                received,
+            )
+            .map(|(_, path, _)| path)
+            .unwrap()
+    };
+
+    for (time, expected) in [
+        // Spring forward: 01:59 is followed by 03:00.
+        ("2024-03-31T00:30:00Z", "events/2024/03/31/01"),
+        ("2024-03-31T01:30:00Z", "events/2024/03/31/03"),
+        // Fall back: both hours are 02:xx locally.
+        ("2024-10-27T00:30:00Z", "events/2024/10/27/02"),
+        ("2024-10-27T01:30:00Z", "events/2024/10/27/02"),
+        ("2024-10-27T02:30:00Z", "events/2024/10/27/03"),
+        // Local midnight is still the previous day in UTC.
+        ("2024-12-31T23:30:00Z", "events/2025/01/01/00"),
+        // Times that can't be parsed fall back to the receive time.
+        ("2024-03-31T02:30:00", "events/2024/05/01/14"),
+        ("not a time", "events/2024/05/01/14"),
+    ] {
+        assert_eq!(path(time), expected, "{time}");
+    }
+}
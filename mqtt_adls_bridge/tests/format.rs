@@ -0,0 +1,234 @@
+//! Tests of encoding batches as Parquet and compressed JSON.
+
+use arrow_json::LineDelimitedWriter;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use mqtt_adls_bridge::format::{
+    compress, to_parquet, Column, ColumnType, Compression, FileFormat, Format,
+};
+use parquet::{
+    arrow::arrow_reader::ParquetRecordBatchReaderBuilder,
+    basic::Compression as ParquetCompression,
+    file::reader::{FileReader, SerializedFileReader},
+};
+use serde_json::{json, Value};
+use std::io::Read;
+
+fn parquet(compression: Option<Compression>, schema: Option<Vec<Column>>) -> FileFormat {
+    FileFormat {
+        format: Format::Parquet,
+        compression,
+        schema,
+    }
+}
+
+fn column(name: &str, column_type: ColumnType) -> Column {
+    Column {
+        name: name.to_string(),
+        column_type,
+    }
+}
+
+fn lines(values: &[Value]) -> Vec<String> {
+    values.iter().map(Value::to_string).collect()
+}
+
+/// Read a Parquet file back as one JSON object per row, leaving out nulls.
+fn rows(file: &Bytes) -> Vec<Value> {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(file.clone())
+        .unwrap()
+        .build()
+        .unwrap();
+    let mut writer = LineDelimitedWriter::new(Vec::new());
+    for batch in reader {
+        writer.write(&batch.unwrap()).unwrap();
+    }
+    writer.finish().unwrap();
+    String::from_utf8(writer.into_inner())
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+/// The names and Parquet types of the columns of a file.
+fn columns(file: &Bytes) -> Vec<String> {
+    let reader = SerializedFileReader::new(file.clone()).unwrap();
+    reader
+        .metadata()
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .map(|c| format!("{}: {}", c.name(), c.physical_type()))
+        .collect()
+}
+
+#[test]
+fn schemas_are_inferred_from_the_batch() {
+    let values = [
+        json!({"machine": "m1", "count": 1, "ok": true}),
+        json!({"machine": "m2", "count": 2, "speed": 1.5}),
+    ];
+
+    let file = to_parquet(&lines(&values), &parquet(None, None)).unwrap();
+
+    // Fields are read in order of their name, so that is the column order.
+    assert_eq!(
+        columns(&file),
+        [
+            "count: INT64",
+            "machine: BYTE_ARRAY",
+            "ok: BOOLEAN",
+            "speed: DOUBLE"
+        ]
+    );
+    assert_eq!(
+        rows(&file),
+        [
+            json!({"machine": "m1", "count": 1, "ok": true}),
+            json!({"machine": "m2", "count": 2, "speed": 1.5}),
+        ]
+    );
+}
+
+#[test]
+fn explicit_schemas_select_and_convert_columns() {
+    let schema = vec![
+        column("machine", ColumnType::String),
+        column("count", ColumnType::Int32),
+        column("timestamp", ColumnType::Timestamp),
+    ];
+    let values = [
+        json!({"machine": "m1", "count": 1, "timestamp": "2024-05-01T14:00:00+02:00", "extra": 1}),
+        json!({"machine": "m2", "timestamp": 1714564800123_i64}),
+    ];
+
+    let file = to_parquet(&lines(&values), &parquet(None, Some(schema))).unwrap();
+
+    assert_eq!(
+        columns(&file),
+        ["machine: BYTE_ARRAY", "count: INT32", "timestamp: INT64"]
+    );
+    // Fields outside of the schema are dropped, missing ones are null.
+    // Timestamps are stored in UTC, from RFC 3339 and from milliseconds.
+    assert_eq!(
+        rows(&file),
+        [
+            json!({"machine": "m1", "count": 1, "timestamp": "2024-05-01T12:00:00Z"}),
+            json!({"machine": "m2", "timestamp": "2024-05-01T12:00:00.123Z"}),
+        ]
+    );
+}
+
+#[test]
+fn lines_that_are_not_objects_are_rejected() {
+    let schema = Some(vec![column("count", ColumnType::Int64)]);
+    for (line, schema) in [
+        ("[1, 2]", None),
+        ("3", None),
+        ("not json", None),
+        ("[1, 2]", schema.clone()),
+        (r#"{"count": "many"}"#, schema),
+    ] {
+        let e = to_parquet(&[line.to_string()], &parquet(None, schema)).unwrap_err();
+        assert!(
+            matches!(e.kind(), azure_core::error::ErrorKind::DataConversion),
+            "{line}: {e:?}"
+        );
+    }
+}
+
+#[test]
+fn parquet_pages_use_the_configured_compression() {
+    let values = [
+        json!({"machine": "m1", "count": 1}),
+        json!({"machine": "m2", "count": 2}),
+    ];
+
+    for (compression, expected) in [
+        (None, "SNAPPY"),
+        (Some(Compression::None), "UNCOMPRESSED"),
+        (Some(Compression::Snappy), "SNAPPY"),
+        (Some(Compression::Gzip), "GZIP"),
+        (Some(Compression::Zstd), "ZSTD"),
+    ] {
+        let file = to_parquet(&lines(&values), &parquet(compression, None)).unwrap();
+
+        let reader = SerializedFileReader::new(file.clone()).unwrap();
+        let codec: ParquetCompression = reader.metadata().row_group(0).column(0).compression();
+        assert!(
+            codec.to_string().starts_with(expected),
+            "{compression:?}: {codec}"
+        );
+        assert_eq!(rows(&file), values, "{compression:?}");
+        // Parquet compresses its pages, not the file.
+        let format = parquet(compression, None);
+        assert_eq!(format.extension(), "parquet");
+        assert_eq!(format.metadata().content_encoding, None);
+        assert_eq!(format.validate(), Ok(()));
+    }
+}
+
+#[test]
+fn json_cannot_use_snappy() {
+    let format = |compression| FileFormat {
+        compression,
+        ..Default::default()
+    };
+
+    assert!(format(Some(Compression::Snappy)).validate().is_err());
+    for compression in [
+        None,
+        Some(Compression::None),
+        Some(Compression::Gzip),
+        Some(Compression::Zstd),
+    ] {
+        assert_eq!(format(compression).validate(), Ok(()), "{compression:?}");
+    }
+}
+
+#[test]
+fn compressed_json_decompresses_to_the_original_lines() {
+    let content = lines(&[json!({"id": 1, "text": "é"}), json!({"id": 2})]).join("\n");
+
+    let gzip = compress(content.clone().into_bytes(), Compression::Gzip).unwrap();
+    let mut decompressed = String::new();
+    GzDecoder::new(gzip.as_slice())
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, content);
+
+    let zstd = compress(content.clone().into_bytes(), Compression::Zstd).unwrap();
+    assert_eq!(
+        zstd::decode_all(zstd.as_slice()).unwrap(),
+        content.as_bytes()
+    );
+
+    for compression in [Compression::None, Compression::Snappy] {
+        let unchanged = compress(content.clone().into_bytes(), compression).unwrap();
+        assert_eq!(unchanged, content.as_bytes(), "{compression:?}");
+    }
+}
+
+#[test]
+fn json_extensions_match_their_content_encoding() {
+    for (compression, extension, content_encoding) in [
+        (None, "json", None),
+        (Some(Compression::None), "json", None),
+        (Some(Compression::Gzip), "json.gz", Some("gzip")),
+        (Some(Compression::Zstd), "json.zst", Some("zstd")),
+    ] {
+        let format = FileFormat {
+            compression,
+            ..Default::default()
+        };
+        assert_eq!(format.extension(), extension, "{compression:?}");
+        let metadata = format.metadata();
+        assert_eq!(metadata.content_type, "application/x-ndjson");
+        assert_eq!(
+            metadata.content_encoding, content_encoding,
+            "{compression:?}"
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Tests of the liveness and readiness checks, and of what keeps the MQTT
+//! client and the write loop alive.
+
+mod common;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use common::{
+    broker::{Broker, Message},
+    init, write_options,
+};
+use mqtt_adls_bridge::{
+    adls::{self, Destination, WriteJob},
+    dead_letter::DeadLetterTarget,
+    health::{Health, HEALTH},
+    mqtt::{run_mqtt_client, MqttConnectOptions, Subscription, Subscriptions},
+    record::RecordFormat,
+    routing::Router,
+    sink::{FileMetadata, Sink},
+};
+use std::time::Duration;
+use tokio::{
+    sync::{mpsc, watch},
+    time::sleep,
+};
+
+/// A sink whose writes never finish.
+struct HangingSink;
+
+#[async_trait]
+impl Sink for HangingSink {
+    async fn write(
+        &self,
+        _container: &str,
+        _file_path: &str,
+        _content: Bytes,
+        _metadata: &FileMetadata,
+    ) -> azure_core::error::Result<()> {
+        std::future::pending().await
+    }
+}
+
+#[test]
+fn liveness_goes_stale_without_heartbeats() {
+    let health = Health::default();
+    let max_stall = Duration::from_millis(100);
+
+    // Nothing has started yet.
+    assert!(health.liveness(max_stall).ok);
+
+    health.mqtt_thread.beat();
+    health.write_loop.beat();
+    assert!(health.liveness(max_stall).ok);
+
+    std::thread::sleep(Duration::from_millis(150));
+    let status = health.liveness(max_stall);
+    assert!(!status.ok);
+    assert!(!status.checks["mqtt_thread"]);
+    assert!(!status.checks["write_loop"]);
+
+    health.write_loop.beat();
+    let status = health.liveness(max_stall);
+    assert!(!status.ok);
+    assert!(!status.checks["mqtt_thread"]);
+    assert!(status.checks["write_loop"]);
+    assert!(health.liveness(Duration::from_secs(10)).ok);
+}
+
+#[test]
+fn readiness_needs_a_connection_and_a_successful_upload() {
+    let health = Health::default();
+    assert!(!health.readiness().ok);
+
+    health.set_connected(true);
+    assert!(health.readiness().ok);
+
+    health.set_upload_succeeded(false);
+    let status = health.readiness();
+    assert!(!status.ok);
+    assert!(status.checks["mqtt_connected"]);
+    assert!(!status.checks["last_upload"]);
+
+    health.set_upload_succeeded(true);
+    assert!(health.readiness().ok);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_loop_goes_stale_while_an_upload_hangs() {
+    init();
+    let (transmitter, receiver) = mpsc::channel(10);
+    let defaults = Destination {
+        container: Some("hanging".to_string()),
+        ..Default::default()
+    };
+    let options = write_options(defaults);
+    let write_loop = adls::handle_write_jobs(&HangingSink, receiver, None, &options);
+
+    let check = async {
+        // Waiting for messages, the loop is alive.
+        sleep(Duration::from_millis(1500)).await;
+        assert!(HEALTH.liveness(Duration::from_millis(1200)).checks["write_loop"]);
+
+        transmitter
+            .send(WriteJob {
+                path: "hangs".to_string(),
+                payload: "{}".to_string(),
+                n_per_file: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(1500)).await;
+        assert!(!HEALTH.liveness(Duration::from_millis(1200)).checks["write_loop"]);
+    };
+
+    tokio::select! {
+        _ = write_loop => panic!("The write loop ended"),
+        _ = check => {}
+    }
+}
+
+#[test]
+fn mqtt_client_goes_stale_while_the_message_callback_hangs() {
+    init();
+    let broker = Broker::start();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    // Room for one job, which is never taken.
+    let (transmitter, jobs) = mpsc::channel(1);
+    let (shutdown, _) = watch::channel(false);
+    let client = runtime.spawn(run_mqtt_client(
+        transmitter,
+        MqttConnectOptions {
+            broker: broker.uri(),
+            client_id: format!("test-{}", uuid::Uuid::new_v4()),
+            ..Default::default()
+        },
+        Router::from_yaml("routes:\n  - { name: all, topic: '#', path: all }\n").unwrap(),
+        Subscriptions {
+            topics: vec![Subscription {
+                topic: "health/#".to_string(),
+                qos: 1,
+            }],
+            max_attempts: 1,
+        },
+        RecordFormat::default(),
+        DeadLetterTarget::Drop,
+        None,
+        shutdown.clone(),
+    ));
+    assert!(broker.wait_until(Duration::from_secs(10), |b| b.subscribes().len() == 1));
+    let max_stall = Duration::from_secs(4);
+    let alive = || HEALTH.liveness(max_stall).checks["mqtt_thread"];
+
+    // Idle, the broker keeps answering the probes.
+    std::thread::sleep(Duration::from_secs(7));
+    assert!(alive());
+
+    // The second message waits for room in the queue forever.
+    broker.publish(&Message::new("health/a", "{}").qos(1));
+    broker.publish(&Message::new("health/b", "{}").qos(1));
+    std::thread::sleep(Duration::from_secs(7));
+    assert!(!alive());
+
+    // Closing the queue releases the callback.
+    drop(jobs);
+    shutdown.send_replace(true);
+    runtime.block_on(client).unwrap().unwrap();
+}
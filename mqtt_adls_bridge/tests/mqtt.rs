@@ -0,0 +1,563 @@
+//! Tests of the MQTT ingest path against an embedded broker.
+
+mod common;
+
+use chrono::{DateTime, Datelike, Utc};
+use common::{
+    broker::{Broker, Message},
+    init,
+};
+use mqtt_adls_bridge::{
+    adls::WriteJob,
+    dead_letter::DeadLetterTarget,
+    format::FileFormat,
+    metrics::METRICS,
+    mqtt::{parse_topics, run_mqtt_client, MqttConnectOptions, Subscription, Subscriptions},
+    record::{Envelope, RecordFormat},
+    routing::Router,
+};
+use serde_json::{json, Value};
+use std::{
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime::Runtime,
+    sync::{
+        mpsc::{self, Receiver},
+        watch,
+    },
+    task::JoinHandle,
+    time::timeout,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The routes shipped with the bridge.
+const ROUTES: &str = include_str!("../routes.yaml");
+
+/// A bridge connected to a test broker, without the write loop.
+struct Bridge {
+    // Dropped first, so a callback waiting for room doesn't keep a failed
+    // test from ending.
+    jobs: Receiver<WriteJob>,
+    runtime: Runtime,
+    shutdown: watch::Sender<bool>,
+    client: JoinHandle<azure_core::error::Result<()>>,
+}
+
+impl Bridge {
+    /// Start the MQTT client and wait until it has subscribed to `topics`.
+    fn start(broker: &Broker, version: u32, routes: &str, records: RecordFormat) -> Bridge {
+        Bridge::with_capacity(broker, version, routes, records, 100)
+    }
+
+    /// Like `start`, with room for `capacity` jobs between the client and
+    /// the write loop.
+    fn with_capacity(
+        broker: &Broker,
+        version: u32,
+        routes: &str,
+        records: RecordFormat,
+        capacity: usize,
+    ) -> Bridge {
+        init();
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let (transmitter, jobs) = mpsc::channel(capacity);
+        let (shutdown, _) = watch::channel(false);
+        let options = MqttConnectOptions {
+            broker: broker.uri(),
+            client_id: format!("test-{}", uuid::Uuid::new_v4()),
+            mqtt_version: version,
+            persistent_session: false,
+            ..Default::default()
+        };
+        let subscriptions = Subscriptions {
+            topics: ["packml/#", "service/#", "other/#"]
+                .iter()
+                .map(|topic| Subscription {
+                    topic: topic.to_string(),
+                    qos: 1,
+                })
+                .collect(),
+            max_attempts: 1,
+        };
+        let client = runtime.spawn(run_mqtt_client(
+            transmitter,
+            options,
+            Router::from_yaml(routes).unwrap(),
+            subscriptions,
+            records,
+            DeadLetterTarget::Path {
+                path: "dead-letter".to_string(),
+                n_per_file: 10,
+            },
+            None,
+            shutdown.clone(),
+        ));
+        assert!(
+            broker.wait_until(TIMEOUT, |b| b.subscribes().len() >= 3),
+            "The bridge did not subscribe"
+        );
+        Bridge {
+            jobs,
+            runtime,
+            shutdown,
+            client,
+        }
+    }
+
+    fn next_job(&mut self) -> WriteJob {
+        self.runtime
+            .block_on(async { timeout(TIMEOUT, self.jobs.recv()).await })
+            .expect("A job arrives in time")
+            .expect("The channel is open")
+    }
+
+    /// Disconnect and check that the channel is closed afterwards.
+    fn stop(mut self) {
+        self.shutdown.send_replace(true);
+        self.runtime.block_on(self.client).unwrap().unwrap();
+        assert!(
+            self.runtime.block_on(self.jobs.recv()).is_none(),
+            "Unexpected job after the last one"
+        );
+    }
+}
+
+/// The job the bridge creates for `payload` on a route without event time,
+/// given the time it was received.
+fn expected(path: String, payload: &str, n_per_file: i32, time: DateTime<Utc>) -> WriteJob {
+    WriteJob {
+        path,
+        payload: serde_json::from_str::<Value>(payload).unwrap().to_string(),
+        n_per_file,
+        format: FileFormat::default(),
+        time: Some(time),
+        ..Default::default()
+    }
+}
+
+/// The receive time of `job`, checked to be between `before` and now.
+fn received_at(job: &WriteJob, before: DateTime<Utc>) -> DateTime<Utc> {
+    let time = job.time.expect("Routed jobs have a time");
+    assert!(before <= time && time <= Utc::now(), "{time}");
+    time
+}
+
+#[test]
+fn packml_events_are_routed_by_their_fields_and_date() {
+    let broker = Broker::start();
+    let mut bridge = Bridge::start(
+        &broker,
+        paho_mqtt::MQTT_VERSION_3_1_1,
+        ROUTES,
+        RecordFormat::default(),
+    );
+    let before = Utc::now();
+    let payload = r#"{"telegramTypeFriendly":"StateChange","telegramTypeVersion":2,"machineIDx":7,"state":"Execute"}"#;
+
+    broker.publish(&Message::new("packml/event/line-1", payload));
+
+    let job = bridge.next_job();
+    let time = received_at(&job, before);
+    let path = format!(
+        "packml/event/telegram_type=StateChange/telegram_version=2/machine_idx=7/year={}/month={}/day={}",
+        time.year(),
+        time.month(),
+        time.day()
+    );
+    assert_eq!(job, expected(path, payload, 10, time));
+    bridge.stop();
+}
+
+#[test]
+fn packml_and_service_status_are_routed() {
+    let broker = Broker::start();
+    let mut bridge = Bridge::start(
+        &broker,
+        paho_mqtt::MQTT_VERSION_3_1_1,
+        ROUTES,
+        RecordFormat::default(),
+    );
+    let before = Utc::now();
+
+    broker.publish(&Message::new(
+        "packml/status/filler",
+        r#"{"ServiceName":"filler","Status":"Running"}"#,
+    ));
+    broker.publish(&Message::new(
+        "service/status/edge-1",
+        r#"{"Host":"edge-1","Uptime":42}"#,
+    ));
+
+    let job = bridge.next_job();
+    let time = received_at(&job, before);
+    assert_eq!(
+        job,
+        expected(
+            "packml/status/service_name=filler".to_string(),
+            r#"{"ServiceName":"filler","Status":"Running"}"#,
+            1,
+            time
+        )
+    );
+    let job = bridge.next_job();
+    let time = received_at(&job, before);
+    assert_eq!(
+        job,
+        expected(
+            "master/status/host=edge-1".to_string(),
+            r#"{"Host":"edge-1","Uptime":42}"#,
+            1,
+            time
+        )
+    );
+    bridge.stop();
+}
+
+#[test]
+fn mqtt5_properties_are_available_to_routes_and_records() {
+    let broker = Broker::start();
+    let routes = r#"
+routes:
+  - name: sited
+    topic: packml/status/#
+    fields:
+      site: $user_properties/site
+      service_name: /ServiceName
+    path: status/site={site}/service_name={service_name}
+    time:
+      field: /ts
+      format: unix
+"#;
+    let records = RecordFormat {
+        envelope: Some(Envelope {
+            received_at: String::new(),
+            ..Default::default()
+        }),
+        properties_field: "properties".to_string(),
+    };
+    let mut bridge = Bridge::start(&broker, paho_mqtt::MQTT_VERSION_5, routes, records);
+
+    broker.publish(
+        &Message::new(
+            "packml/status/x",
+            r#"{"ServiceName":"svc","ts":1660000000}"#,
+        )
+        .qos(1)
+        .retain()
+        .content_type("application/json")
+        .user_property("site", "plant-7"),
+    );
+
+    let job = bridge.next_job();
+    let record = json!({
+        "topic": "packml/status/x",
+        "qos": 1,
+        "retained": true,
+        "properties": {
+            "content_type": "application/json",
+            "user_properties": {"site": "plant-7"}
+        },
+        "payload": {"ServiceName": "svc", "ts": 1660000000}
+    });
+    assert_eq!(
+        job,
+        WriteJob {
+            path: "status/site=plant-7/service_name=svc".to_string(),
+            payload: record.to_string(),
+            n_per_file: 1,
+            time: DateTime::from_timestamp(1660000000, 0),
+            ..Default::default()
+        }
+    );
+    bridge.stop();
+}
+
+#[test]
+fn messages_that_cannot_be_routed_are_dead_lettered() {
+    let broker = Broker::start();
+    let mut bridge = Bridge::start(
+        &broker,
+        paho_mqtt::MQTT_VERSION_3_1_1,
+        ROUTES,
+        RecordFormat::default(),
+    );
+
+    broker.publish(&Message::new("packml/status/x", "not json"));
+    // A status without a service name matches no route.
+    broker.publish(&Message::new("packml/status/x", r#"{"Status":"Running"}"#));
+
+    for (reason, payload) in [
+        ("unparseable", "not json"),
+        ("unroutable", r#"{"Status":"Running"}"#),
+    ] {
+        let job = bridge.next_job();
+        assert_eq!(job.path, format!("dead-letter/reason={reason}"));
+        assert_eq!(job.n_per_file, 10);
+        let letter: Value = serde_json::from_str(&job.payload).unwrap();
+        assert_eq!(letter["topic"], "packml/status/x");
+        assert_eq!(letter["reason"], reason);
+        // The file is named after the time the message was received.
+        let received_at: DateTime<Utc> = letter["received_at"].as_str().unwrap().parse().unwrap();
+        assert_eq!(job.time, Some(received_at));
+        assert_eq!(
+            base64::decode(letter["payload_base64"].as_str().unwrap()).unwrap(),
+            payload.as_bytes()
+        );
+    }
+    bridge.stop();
+}
+
+#[test]
+fn received_messages_are_counted_by_route_not_topic() {
+    let broker = Broker::start();
+    let routes = r##"
+routes:
+  - name: counted-metrics
+    topic: "other/counted/#"
+    path: "counted"
+"##;
+    let mut bridge = Bridge::start(
+        &broker,
+        paho_mqtt::MQTT_VERSION_3_1_1,
+        routes,
+        RecordFormat::default(),
+    );
+    let received = |route: &str| METRICS.messages_received.with_label_values(&[route]).get();
+    let unroutable = received("unroutable");
+
+    for topic in [
+        "other/counted/machine-1",
+        "other/counted/machine-2",
+        "other/uncounted/machine-3",
+    ] {
+        broker.publish(&Message::new(topic, "{}"));
+        bridge.next_job();
+    }
+    bridge.stop();
+
+    assert_eq!(received("counted-metrics"), 2);
+    assert!(received("unroutable") > unroutable);
+    let exposition = METRICS.render();
+    assert!(
+        exposition
+            .contains(r#"mqtt_adls_bridge_messages_received_total{route="counted-metrics"} 2"#),
+        "{exposition}"
+    );
+    // Topics are not labels, so they can't grow the number of series.
+    assert!(!exposition.contains("machine-"), "{exposition}");
+    assert!(!exposition.contains("topic="), "{exposition}");
+}
+
+#[test]
+fn messages_are_acknowledged_once_queued() {
+    let broker = Broker::start();
+    let mut bridge = Bridge::start(
+        &broker,
+        paho_mqtt::MQTT_VERSION_3_1_1,
+        ROUTES,
+        RecordFormat::default(),
+    );
+
+    broker.publish(&Message::new("packml/status/x", r#"{"ServiceName":"svc"}"#).qos(1));
+
+    assert!(broker.wait_until(TIMEOUT, |b| b.acks() == 1));
+    // The acknowledgement is sent after the job was handed to the write loop.
+    let job = bridge
+        .jobs
+        .try_recv()
+        .expect("The job is queued before the ack");
+    assert_eq!(job.path, "packml/status/service_name=svc");
+    bridge.stop();
+}
+
+#[test]
+fn acknowledgements_wait_while_the_queue_is_full() {
+    let broker = Broker::start();
+    let mut bridge = Bridge::with_capacity(
+        &broker,
+        paho_mqtt::MQTT_VERSION_3_1_1,
+        ROUTES,
+        RecordFormat::default(),
+        1,
+    );
+
+    for host in ["a", "b", "c"] {
+        let payload = format!(r#"{{"Host":"{host}"}}"#);
+        broker.publish(&Message::new(&format!("service/status/{host}"), payload).qos(1));
+    }
+
+    // The first job fills the queue, so the second one waits for room and
+    // holds back its acknowledgement.
+    assert!(broker.wait_until(TIMEOUT, |b| b.acks() == 1));
+    assert!(!broker.wait_until(Duration::from_millis(500), |b| b.acks() > 1));
+
+    let paths: Vec<String> = (0..3).map(|_| bridge.next_job().path).collect();
+    assert_eq!(
+        paths,
+        [
+            "master/status/host=a",
+            "master/status/host=b",
+            "master/status/host=c"
+        ]
+    );
+    assert!(broker.wait_until(TIMEOUT, |b| b.acks() == 3));
+    bridge.stop();
+}
+
+#[test]
+fn bridge_exits_without_acknowledging_if_the_write_ahead_log_fails() {
+    init();
+    let broker = Broker::start();
+    let dir = common::dead_letter_dir().join(format!("wal-{}", uuid::Uuid::new_v4()));
+    let wal = dir.join("wal");
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut bridge = Command::new(env!("CARGO_BIN_EXE_mqtt_adls_bridge"))
+        .current_dir(&dir)
+        .env_remove("CONFIG_FILE")
+        .env("MQTT_BROKER", broker.uri())
+        .env("MQTT_CLIENT_ID", format!("test-{}", uuid::Uuid::new_v4()))
+        .env("MQTT_TOPICS", "packml/#:1")
+        .env("SINK", "local")
+        .env("LOCAL_SINK_DIR", dir.join("data"))
+        .env("WAL_DIR", &wal)
+        .env("HTTP_ADDR", "")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    assert!(
+        broker.wait_until(TIMEOUT, |b| b.subscribes().len() == 1),
+        "The bridge did not subscribe"
+    );
+    // Entries can't be written once the directory is gone.
+    std::fs::remove_dir_all(&wal).unwrap();
+
+    broker.publish(&Message::new("packml/status/x", r#"{"ServiceName":"svc"}"#).qos(1));
+
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        if let Some(status) = bridge.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            bridge.kill().unwrap();
+            panic!("The bridge did not exit");
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(status.code(), Some(1));
+    assert_eq!(broker.acks(), 0);
+    assert!(common::files_below(&dir.join("data")).is_empty());
+}
+
+#[test]
+fn bridge_reconnects_and_resubscribes_after_a_broker_restart() {
+    let broker = Broker::start();
+    let mut bridge = Bridge::start(
+        &broker,
+        paho_mqtt::MQTT_VERSION_5,
+        ROUTES,
+        RecordFormat::default(),
+    );
+    let reconnects = METRICS.mqtt_reconnects.get();
+
+    broker.publish(&Message::new("service/status/a", r#"{"Host":"a"}"#));
+    assert_eq!(bridge.next_job().path, "master/status/host=a");
+
+    broker.stop();
+    assert!(
+        broker.wait_until(TIMEOUT, |_| METRICS.mqtt_reconnects.get() > reconnects),
+        "The bridge did not notice the lost connection"
+    );
+    broker.restart();
+
+    // A clean session starts without subscriptions, so they are made again.
+    assert!(
+        broker.wait_until(Duration::from_secs(20), |b| b.connects() == 2
+            && b.subscribes().len() == 6),
+        "The bridge did not resubscribe: {:?}",
+        broker.subscribes()
+    );
+    broker.publish(&Message::new("service/status/b", r#"{"Host":"b"}"#));
+    assert_eq!(bridge.next_job().path, "master/status/host=b");
+    bridge.stop();
+}
+
+#[test]
+fn rejected_subscriptions_are_retried_then_stop_the_bridge() {
+    init();
+    let broker = Broker::start();
+    broker.reject("denied/#");
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap();
+    let (transmitter, _jobs) = mpsc::channel(10);
+    let (shutdown, _) = watch::channel(false);
+    let client = runtime.spawn(run_mqtt_client(
+        transmitter,
+        MqttConnectOptions {
+            broker: broker.uri(),
+            client_id: format!("test-{}", uuid::Uuid::new_v4()),
+            ..Default::default()
+        },
+        Router::from_yaml(ROUTES).unwrap(),
+        Subscriptions {
+            topics: parse_topics("packml/#:1,denied/#:1").unwrap(),
+            max_attempts: 2,
+        },
+        RecordFormat::default(),
+        DeadLetterTarget::Drop,
+        None,
+        shutdown.clone(),
+    ));
+
+    let result = runtime
+        .block_on(async { timeout(TIMEOUT, client).await })
+        .expect("The bridge did not stop")
+        .unwrap();
+
+    assert!(result.is_err());
+    assert!(*shutdown.borrow());
+    // Only the rejected subscription is made again.
+    assert_eq!(broker.subscribes(), ["packml/#", "denied/#", "denied/#"]);
+}
+
+#[test]
+fn topics_are_parsed_with_their_qos() {
+    let topics = parse_topics("packml/#:2, service/#:0,$share/bridges/a/+,plain").unwrap();
+    let parsed: Vec<(&str, i32)> = topics
+        .iter()
+        .map(|sub| (sub.topic.as_str(), sub.qos))
+        .collect();
+    assert_eq!(
+        parsed,
+        [
+            ("packml/#", 2),
+            ("service/#", 0),
+            ("$share/bridges/a/+", 1),
+            ("plain", 1)
+        ]
+    );
+
+    for topics in [
+        "packml/#:3",
+        "packml/#:99999999999",
+        "$share/bridges",
+        "$share//a/#",
+        "$share/bri+dges/a",
+        "$share/bridges/a/#/b",
+        "a/#/b",
+        " , ",
+    ] {
+        assert!(parse_topics(topics).is_err(), "{topics}");
+    }
+}
@@ -0,0 +1,387 @@
+//! Tests of reading archived messages and routing them like live ones.
+
+mod common;
+
+use chrono::{DateTime, Utc};
+use common::broker::Message;
+use common::init;
+use flate2::{write::GzEncoder, Compression};
+use mqtt_adls_bridge::{
+    mqtt::MqttVersion,
+    record::{Envelope, RecordFormat},
+    replay::{
+        archive_files, parse_time, read_capture, read_file, read_messages, replay_files,
+        ArchivedMessage, ReplayOptions, ReplayStats,
+    },
+    routing::Router,
+};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc;
+
+/// The routes shipped with the bridge.
+const ROUTES: &str = include_str!("../routes.yaml");
+
+/// An empty directory of its own below the temporary directory.
+fn archive_dir(name: &str) -> PathBuf {
+    let dir = common::dead_letter_dir().join("replay").join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn enveloped_records_and_dead_letters_are_read() {
+    init();
+    let lines = r#"
+{"topic":"service/status/a","received_at":"2024-05-01T12:00:00Z","qos":1,"retained":true,"payload":{"Host":"a"},"properties":{"user_properties":{"site":"p7"}}}
+not a message
+{"topic":"packml/status/x","reason":"unparseable","payload_base64":"bm90IGpzb24="}
+{"topic":"packml/status/x"}
+"#;
+
+    let messages = read_messages(lines.as_bytes(), "test")
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0].topic, "service/status/a");
+    assert_eq!(messages[0].payload, br#"{"Host":"a"}"#);
+    assert_eq!((messages[0].qos, messages[0].retained), (1, true));
+    assert_eq!(
+        messages[0].received_at,
+        Some("2024-05-01T12:00:00Z".parse().unwrap())
+    );
+    assert_eq!(messages[0].properties.user_properties["site"], "p7");
+    assert_eq!(messages[1].payload, b"not json");
+    assert_eq!(messages[1].received_at, None);
+}
+
+#[test]
+fn messages_are_routed_at_the_time_they_were_received() {
+    let router = Router::from_yaml(ROUTES).unwrap();
+    let message = ArchivedMessage::from_json(
+        r#"{"topic":"packml/event/l1","received_at":"2022-08-08T23:30:00Z","payload":{"telegramTypeFriendly":"StateChange","telegramTypeVersion":2,"machineIDx":7}}"#,
+    )
+    .unwrap();
+
+    let job = message.route(&router, &RecordFormat::default()).unwrap();
+
+    assert_eq!(
+        job.path,
+        "packml/event/telegram_type=StateChange/telegram_version=2/machine_idx=7/year=2022/month=8/day=8"
+    );
+    assert_eq!(job.time, message.received_at);
+}
+
+#[test]
+fn records_are_written_like_live_ones() {
+    let router = Router::from_yaml(ROUTES).unwrap();
+    let records = RecordFormat {
+        envelope: Some(Envelope::default()),
+        properties_field: "properties".to_string(),
+    };
+    let line = json!({
+        "topic": "packml/status/x",
+        "received_at": "2024-05-01T12:00:00Z",
+        "qos": 1,
+        "retained": false,
+        "properties": {"content_type": "application/json"},
+        "payload": {"ServiceName": "svc"}
+    });
+    let message = ArchivedMessage::from_json(&line.to_string()).unwrap();
+
+    let job = message.route(&router, &records).unwrap();
+
+    // An enveloped record is read back as the message it came from.
+    let record: Value = serde_json::from_str(&job.payload).unwrap();
+    assert_eq!(record, line);
+}
+
+#[test]
+fn unroutable_messages_become_dead_letters() {
+    let router = Router::from_yaml(ROUTES).unwrap();
+    let message = ArchivedMessage::from_json(
+        r#"{"topic":"packml/status/x","received_at":"2022-08-08T23:30:00Z","payload":{"Status":"Running"}}"#,
+    )
+    .unwrap();
+
+    let letter = message
+        .route(&router, &RecordFormat::default())
+        .unwrap_err();
+
+    assert_eq!(letter.reason, "unroutable");
+    assert_eq!(letter.topic, "packml/status/x");
+    // Dead letters keep the time the message was originally received.
+    assert_eq!(Some(letter.received_at), message.received_at);
+
+    let mut message = message;
+    message.payload = b"not json".to_vec();
+    let letter = message
+        .route(&router, &RecordFormat::default())
+        .unwrap_err();
+    assert_eq!(letter.reason, "unparseable");
+    assert_eq!(Some(letter.received_at), message.received_at);
+}
+
+#[test]
+fn archives_are_read_recursively_in_order() {
+    init();
+    let dir = archive_dir("recursive");
+    let line = |host: &str| format!("{{\"topic\":\"service/status/{host}\",\"payload\":{{}}}}\n");
+    fs::create_dir_all(dir.join("b/c")).unwrap();
+    fs::write(dir.join("b/2.json"), line("b2")).unwrap();
+    fs::write(dir.join("a.json"), line("a")).unwrap();
+    fs::write(dir.join("b/c/x.parquet"), "PAR1").unwrap();
+    fs::write(dir.join(".hidden"), line("hidden")).unwrap();
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(line("b1").as_bytes()).unwrap();
+    fs::write(dir.join("b/1.json.gz"), gz.finish().unwrap()).unwrap();
+
+    let files = archive_files(&dir).unwrap();
+
+    assert_eq!(
+        files,
+        [
+            dir.join("a.json"),
+            dir.join("b/1.json.gz"),
+            dir.join("b/2.json")
+        ]
+    );
+    let topics: Vec<String> = files
+        .iter()
+        .flat_map(|file| read_file(file, MqttVersion::V3_1_1).unwrap())
+        .map(Result::unwrap)
+        .map(|message| message.topic)
+        .collect();
+    assert_eq!(
+        topics,
+        ["service/status/a", "service/status/b1", "service/status/b2"]
+    );
+}
+
+#[test]
+fn messages_without_a_receive_time_count_as_received_now() {
+    let message =
+        ArchivedMessage::from_json(r#"{"topic":"service/status/a","payload":{"Host":"a"}}"#)
+            .unwrap();
+    let before = Utc::now();
+
+    let job = message
+        .route(
+            &Router::from_yaml(ROUTES).unwrap(),
+            &RecordFormat::default(),
+        )
+        .unwrap();
+
+    let time: DateTime<Utc> = job.time.unwrap();
+    assert!(before <= time && time <= Utc::now());
+}
+
+#[test]
+fn mqtt3_captures_are_decoded() {
+    init();
+    let mut capture = vec![0x20, 2, 0, 0]; // CONNACK
+    capture.extend(Message::new("service/status/a", r#"{"Host":"a"}"#).encode(4, 0));
+    capture.extend([0xd0, 0]); // PINGRESP
+    capture.extend(
+        Message::new("packml/status/x", "{}")
+            .qos(1)
+            .retain()
+            .encode(4, 7),
+    );
+    // The capture was cut off within this packet.
+    capture.extend(&Message::new("service/status/b", "{}").encode(4, 0)[..5]);
+
+    let messages = read_capture(capture.as_slice(), MqttVersion::V3_1_1, "test")
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+
+    assert_eq!(
+        messages,
+        [
+            ArchivedMessage {
+                topic: "service/status/a".to_string(),
+                payload: br#"{"Host":"a"}"#.to_vec(),
+                qos: 0,
+                retained: false,
+                received_at: None,
+                properties: Default::default(),
+            },
+            ArchivedMessage {
+                topic: "packml/status/x".to_string(),
+                payload: b"{}".to_vec(),
+                qos: 1,
+                retained: true,
+                received_at: None,
+                properties: Default::default(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn mqtt5_captures_keep_properties_and_topic_aliases() {
+    init();
+    let mut capture = Message::new("packml/status/x", r#"{"ServiceName":"svc"}"#)
+        .qos(1)
+        .content_type("application/json")
+        .user_property("site", "plant-7")
+        .encode(5, 1);
+    // A topic alias is set with a topic and used without one.
+    let publish = |topic: &str| {
+        let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+        body.extend(topic.as_bytes());
+        body.extend([3, 0x23, 0, 9]);
+        body.extend(b"{}");
+        let mut packet = vec![0x30, body.len() as u8];
+        packet.extend(body);
+        packet
+    };
+    capture.extend(publish("service/status/a"));
+    capture.extend(publish(""));
+
+    let messages = read_capture(capture.as_slice(), MqttVersion::V5, "test")
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap();
+
+    assert_eq!(messages.len(), 3);
+    let properties = &messages[0].properties;
+    assert_eq!(properties.content_type.as_deref(), Some("application/json"));
+    assert_eq!(properties.user_properties["site"], "plant-7");
+    assert_eq!(messages[0].payload, br#"{"ServiceName":"svc"}"#);
+    assert_eq!(messages[1].topic, "service/status/a");
+    assert_eq!(messages[2].topic, "service/status/a");
+    assert_eq!(messages[2].payload, b"{}");
+}
+
+#[test]
+fn capture_files_are_recognized_by_their_extension() {
+    let dir = archive_dir("capture");
+    let capture = Message::new("service/status/a", r#"{"Host":"a"}"#).encode(4, 0);
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    gz.write_all(&capture).unwrap();
+    fs::write(dir.join("broker.mqtt"), &capture).unwrap();
+    fs::write(dir.join("broker.mqtt.gz"), gz.finish().unwrap()).unwrap();
+    let zst = zstd::encode_all(capture.as_slice(), 0).unwrap();
+    fs::write(dir.join("broker.mqtt.zst"), zst).unwrap();
+
+    for file in ["broker.mqtt", "broker.mqtt.gz", "broker.mqtt.zst"] {
+        let messages: Vec<_> = read_file(&dir.join(file), MqttVersion::V3_1_1)
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(messages.len(), 1, "{file}");
+        assert_eq!(messages[0].topic, "service/status/a");
+    }
+}
+
+#[test]
+fn times_are_dates_or_rfc3339() {
+    assert_eq!(
+        parse_time("2024-05-01").unwrap(),
+        "2024-05-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap()
+    );
+    assert_eq!(
+        parse_time("2024-05-01T14:00:00+02:00").unwrap(),
+        "2024-05-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap()
+    );
+    assert!(parse_time("yesterday").is_err());
+}
+
+#[test]
+fn replays_are_filtered_by_time_and_rate_limited() {
+    init();
+    let dir = archive_dir("filtered");
+    let lines: String = (1..=6)
+        .map(|day| {
+            format!(
+                "{{\"topic\":\"service/status/h{day}\",\"received_at\":\"2024-05-0{day}T12:00:00Z\",\"payload\":{{\"Host\":\"h{day}\"}}}}\n"
+            )
+        })
+        .collect();
+    fs::write(
+        dir.join("archive.json"),
+        lines + "{\"topic\":\"other\",\"payload\":{}}\n",
+    )
+    .unwrap();
+    let options = ReplayOptions {
+        rate: Some(20.0),
+        from: Some(parse_time("2024-05-02").unwrap()),
+        until: Some(parse_time("2024-05-05T12:00:00Z").unwrap()),
+        ..Default::default()
+    };
+    let (tx, mut jobs) = mpsc::channel(16);
+    let started = Instant::now();
+
+    let stats = replay_files(
+        &archive_files(&dir).unwrap(),
+        &Router::from_yaml(ROUTES).unwrap(),
+        &RecordFormat::default(),
+        &options,
+        &tx,
+    )
+    .unwrap();
+
+    assert_eq!(
+        stats,
+        ReplayStats {
+            files: 1,
+            routed: 3,
+            filtered: 3,
+            unroutable: 1,
+        }
+    );
+    // The first message is due right away, the third after 2 / 20 seconds.
+    assert!(started.elapsed() >= Duration::from_millis(100));
+    let paths: Vec<String> = std::iter::from_fn(|| jobs.try_recv().ok())
+        .map(|job| job.path)
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            "master/status/host=h2",
+            "master/status/host=h3",
+            "master/status/host=h4"
+        ]
+    );
+}
+
+/// A reader that repeats its bytes forever.
+struct Endless(Vec<u8>, usize);
+
+impl Read for Endless {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.0.len() - self.1);
+        buf[..n].copy_from_slice(&self.0[self.1..self.1 + n]);
+        self.1 = (self.1 + n) % self.0.len();
+        Ok(n)
+    }
+}
+
+#[test]
+fn messages_are_read_as_they_are_taken() {
+    let line = br#"{"topic":"service/status/a","payload":{"Host":"a"}}
+"#;
+    let lines = io::BufReader::new(Endless(line.to_vec(), 0));
+    let messages: Vec<_> = read_messages(lines, "endless").take(3).collect();
+    assert_eq!(messages.len(), 3);
+    assert_eq!(messages[2].as_ref().unwrap().topic, "service/status/a");
+
+    let packet = Message::new("service/status/b", "{}").encode(4, 0);
+    let packets = Endless(packet, 0);
+    let messages: Vec<_> = read_capture(packets, MqttVersion::V3_1_1, "endless")
+        .take(3)
+        .collect();
+    assert_eq!(messages.len(), 3);
+    assert_eq!(messages[2].as_ref().unwrap().topic, "service/status/b");
+
+    // Records written without the envelope have no topic.
+    let plain = read_messages(&br#"{"Host":"a"}"#[..], "plain");
+    assert_eq!(plain.count(), 0);
+}
@@ -0,0 +1,235 @@
+//! Tests of matching topics and rendering paths from routes.
+
+use chrono::{DateTime, Utc};
+use mqtt_adls_bridge::{
+    adls::Destination,
+    routing::{topic_matches, MessageProperties, Router},
+};
+use serde_json::{json, Value};
+
+/// The routes shipped with the bridge.
+const ROUTES: &str = include_str!("../routes.yaml");
+
+fn time() -> DateTime<Utc> {
+    "2024-05-01T12:00:00Z".parse().unwrap()
+}
+
+/// The name of the route `payload` on `topic` matches and the rendered path.
+fn route(router: &Router, topic: &str, payload: Value) -> Option<(String, String)> {
+    router
+        .route(topic, &payload, &MessageProperties::default(), time())
+        .map(|(route, path, _)| (route.name.clone(), path))
+}
+
+/// A router with a single route on `topic` rendering `path`.
+fn single(topic: &str, path: &str) -> std::io::Result<Router> {
+    Router::from_yaml(&format!(
+        "routes:\n  - {{ name: r, topic: '{topic}', path: '{path}', fields: {{ id: /id }} }}\n"
+    ))
+}
+
+#[test]
+fn wildcards_match_whole_levels() {
+    for (filter, topic, matches) in [
+        ("#", "a", true),
+        ("#", "a/b/c", true),
+        ("a/#", "a", true),
+        ("a/#", "a/b/c", true),
+        ("a/#", "ab", false),
+        ("a/+", "a/b", true),
+        ("a/+", "a/", true),
+        ("a/+", "a", false),
+        ("a/+", "a/b/c", false),
+        ("a/+/c", "a/b/c", true),
+        ("+/+", "/a", true),
+        ("+", "a/b", false),
+        ("a/b", "a/b", true),
+        ("a/b", "a/b/c", false),
+        ("a/b/c", "a/b", false),
+        ("a/b", "A/b", false),
+    ] {
+        assert_eq!(topic_matches(filter, topic), matches, "{filter} {topic}");
+    }
+}
+
+#[test]
+fn invalid_filters_and_templates_are_rejected() {
+    for filter in ["a/#/b", "a#", "a/b+", "#/a", "a/+b/c"] {
+        let e = single(filter, "x/{id}").unwrap_err();
+        assert!(
+            e.to_string().contains("Invalid topic filter"),
+            "{filter}: {e}"
+        );
+    }
+    for filter in ["#", "+", "a/+/#", "+/+/+"] {
+        assert!(single(filter, "x/{id}").is_ok(), "{filter}");
+    }
+    for path in ["x/{nope}", "x/{yyyy", "x/{id}/{}"] {
+        let e = single("a/#", path).unwrap_err();
+        assert!(
+            e.to_string().contains("Invalid path template"),
+            "{path}: {e}"
+        );
+    }
+}
+
+#[test]
+fn routes_with_missing_fields_fall_through() {
+    let router = Router::from_yaml(
+        r##"
+routes:
+  - name: by-id
+    topic: "a/#"
+    fields: { id: /id, kind: /meta/kind }
+    path: "ids/{kind}/{id}"
+  - name: catch-all
+    topic: "#"
+    path: "other/{yyyy}/{mm}/{dd}/{hh}/{m}/{d}/{h}"
+"##,
+    )
+    .unwrap();
+
+    assert_eq!(
+        route(&router, "a/b", json!({"id": 7, "meta": {"kind": "x"}})),
+        Some(("by-id".into(), "ids/x/7".into()))
+    );
+    // A null field counts as missing, so is a nested one that isn't there.
+    for payload in [
+        json!({"id": null, "meta": {"kind": "x"}}),
+        json!({"id": 7, "meta": {}}),
+        json!({"id": 7, "meta": "x"}),
+        json!([1, 2]),
+    ] {
+        assert_eq!(
+            route(&router, "a/b", payload),
+            Some(("catch-all".into(), "other/2024/05/01/12/5/1/12".into()))
+        );
+    }
+}
+
+#[test]
+fn baseline_topics_are_routed_as_before() {
+    let router = Router::from_yaml(ROUTES).unwrap();
+    let event =
+        json!({"telegramTypeFriendly": "StateChange", "telegramTypeVersion": 2, "machineIDx": 7});
+
+    assert_eq!(
+        route(&router, "packml/event/line-1", event.clone()).unwrap().1,
+        "packml/event/telegram_type=StateChange/telegram_version=2/machine_idx=7/year=2024/month=5/day=1"
+    );
+    assert_eq!(
+        route(&router, "packml/status/x", json!({"ServiceName": "svc"}))
+            .unwrap()
+            .1,
+        "packml/status/service_name=svc"
+    );
+    assert_eq!(
+        route(&router, "service/status/x/y", json!({"Host": "h"}))
+            .unwrap()
+            .1,
+        "master/status/host=h"
+    );
+    // Strings are written without quotes.
+    assert_eq!(
+        route(&router, "service/status", json!({"Host": 7}))
+            .unwrap()
+            .1,
+        "master/status/host=7"
+    );
+    // An event on a status topic is not routed as an event.
+    assert_eq!(route(&router, "packml/status/x", event.clone()), None);
+    // The hard-coded routing matched these as well, the built-in routes don't.
+    assert_eq!(route(&router, "packml/line-1/event", event), None);
+    assert_eq!(
+        route(&router, "service-a/status", json!({"Host": "h"})),
+        None
+    );
+}
+
+#[test]
+fn field_values_cannot_escape_the_path_of_a_route() {
+    let router = Router::from_yaml(ROUTES).unwrap();
+
+    for host in [
+        "../../etc",
+        "..",
+        ".",
+        "/etc/passwd",
+        "a/b",
+        r"..\..\x",
+        "a\u{0}b",
+    ] {
+        assert_eq!(
+            route(&router, "service/status/x", json!({ "Host": host })),
+            None,
+            "{host}"
+        );
+    }
+    // Dots within a value are fine.
+    assert_eq!(
+        route(&router, "service/status/x", json!({"Host": "h..1.example"}))
+            .unwrap()
+            .1,
+        "master/status/host=h..1.example"
+    );
+}
+
+#[test]
+fn routes_sharing_a_location_must_agree_on_how_it_is_written() {
+    let defaults = Destination {
+        container: Some("default".to_string()),
+        ..Default::default()
+    };
+    let check = |routes: &str| {
+        Router::from_yaml(&format!("routes:\n{routes}"))
+            .unwrap()
+            .check_locations(&defaults)
+    };
+
+    for routes in [
+        // A field value can render the literal part of the other path.
+        "  - { name: a, topic: a, path: 'data/{id}', fields: { id: /id } }\n\
+         \x20 - { name: b, topic: b, path: 'data/x', n_per_file: 5 }\n",
+        // A field value can render a date.
+        "  - { name: a, topic: a, path: 'data/{yyyy}' }\n\
+         \x20 - { name: b, topic: b, path: 'data/{id}', fields: { id: /id }, format: parquet }\n",
+        // {m} renders one or two digits.
+        "  - { name: a, topic: a, path: 'data/{m}' }\n\
+         \x20 - { name: b, topic: b, path: 'data/{mm}', compression: gzip }\n",
+        // The container of the defaults is the one of the other route.
+        "  - { name: a, topic: a, path: 'data' }\n\
+         \x20 - { name: b, topic: b, path: 'data', container: default, extension: txt }\n",
+    ] {
+        let e = check(routes).unwrap_err();
+        assert!(
+            e.to_string().contains("Routes 'a' and 'b'"),
+            "{routes}: {e}"
+        );
+    }
+
+    for routes in [
+        // The same settings.
+        "  - { name: a, topic: a, path: 'data/{id}', fields: { id: /id } }\n\
+         \x20 - { name: b, topic: b, path: 'data/x' }\n",
+        // The default extension of the format.
+        "  - { name: a, topic: a, path: 'data' }\n\
+         \x20 - { name: b, topic: b, path: 'data', extension: json }\n",
+        // A literal part that differs.
+        "  - { name: a, topic: a, path: 'data/year={yyyy}' }\n\
+         \x20 - { name: b, topic: b, path: 'data/year=x{id}', fields: { id: /id }, n_per_file: 5 }\n",
+        // A field value renders a single level only.
+        "  - { name: a, topic: a, path: 'data/{id}', fields: { id: /id } }\n\
+         \x20 - { name: b, topic: b, path: 'data/x/y', n_per_file: 5 }\n",
+        // Another container or prefix.
+        "  - { name: a, topic: a, path: 'data' }\n\
+         \x20 - { name: b, topic: b, path: 'data', container: other, n_per_file: 5 }\n",
+        "  - { name: a, topic: a, path: 'data' }\n\
+         \x20 - { name: b, topic: b, path: 'data', prefix: raw, n_per_file: 5 }\n",
+    ] {
+        assert!(check(routes).is_ok(), "{routes}");
+    }
+    assert!(Router::from_yaml(ROUTES)
+        .unwrap()
+        .check_locations(&defaults)
+        .is_ok());
+}
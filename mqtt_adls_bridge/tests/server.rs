@@ -0,0 +1,82 @@
+//! Tests of the HTTP endpoints for metrics and probes.
+
+use mqtt_adls_bridge::{health::HEALTH, server::start_server};
+use serde_json::Value;
+use std::{
+    net::{SocketAddr, TcpListener},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::sleep,
+};
+
+/// A local address that nothing listens on.
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// GET `path` and return the status code and the body.
+async fn get(addr: SocketAddr, path: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(format!("GET {path} HTTP/1.0\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, body.to_string())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn probes_report_stalled_loops_and_missing_connections() {
+    let addr = free_addr();
+    let server = start_server(Some(addr), Duration::from_millis(300))
+        .unwrap()
+        .unwrap();
+
+    // Nothing has started yet, so nothing has stalled.
+    let (status, body) = get(addr, "/healthz").await;
+    assert_eq!(status, 200, "{body}");
+
+    HEALTH.mqtt_thread.beat();
+    HEALTH.write_loop.beat();
+    sleep(Duration::from_millis(400)).await;
+    HEALTH.write_loop.beat();
+    let (status, body) = get(addr, "/healthz").await;
+    assert_eq!(status, 503, "{body}");
+    let checks: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(checks["ok"], false);
+    assert_eq!(checks["checks"]["mqtt_thread"], false);
+    assert_eq!(checks["checks"]["write_loop"], true);
+
+    HEALTH.mqtt_thread.beat();
+    assert_eq!(get(addr, "/healthz").await.0, 200);
+
+    let (status, body) = get(addr, "/readyz").await;
+    assert_eq!(status, 503, "{body}");
+    let checks: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(checks["checks"]["mqtt_connected"], false);
+    HEALTH.set_connected(true);
+    assert_eq!(get(addr, "/readyz").await.0, 200);
+
+    let (status, body) = get(addr, "/metrics").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("mqtt_adls_bridge_mqtt_connected 0"), "{body}");
+    assert_eq!(get(addr, "/other").await.0, 404);
+
+    server.abort();
+}
+
+#[test]
+fn no_address_serves_nothing() {
+    assert!(start_server(None, Duration::from_secs(1))
+        .unwrap()
+        .is_none());
+}